@@ -0,0 +1,462 @@
+use crate::state::ShellState;
+use std::fmt;
+
+#[derive(Debug)]
+pub struct ArithError {
+    expression: String,
+    message: String,
+}
+
+impl fmt::Display for ArithError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.expression.trim(), self.message)
+    }
+}
+
+impl std::error::Error for ArithError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// Longest first, so that `<<=` is not read as `<` followed by `<=`
+const OPERATORS: [&str; 39] = [
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=", "/=",
+    "%=", "+=", "-=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "=", "!", "~", "&", "^",
+    "|", "?", ":", ",", "(", ")",
+];
+
+const ASSIGNMENTS: [&str; 11] = [
+    "=", "*=", "/=", "%=", "+=", "-=", "<<=", ">>=", "&=", "^=", "|=",
+];
+
+// Binary operators from the loosest binding to the tightest
+const LEVELS: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+// A variable's value is itself an expression, which could name the variable
+// again. Every level evaluates a whole expression on the stack, so the limit
+// has to fit the stack of the main thread.
+const MAX_DEPTH: usize = 128;
+
+// Integer literals: decimal, octal with a leading 0, hex with 0x and any
+// base from 2 to 64 as `base#digits`
+fn parse_number(text: &str) -> Option<i64> {
+    let (base, digits) = if let Some((base, digits)) = text.split_once('#') {
+        (
+            base.parse().ok().filter(|base| (2..=64).contains(base))?,
+            digits,
+        )
+    } else if let Some(digits) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        (16, digits)
+    } else if text.len() > 1 && text.starts_with('0') {
+        (8, &text[1..])
+    } else {
+        (10, text)
+    };
+    if digits.is_empty() {
+        return None;
+    }
+
+    digits.chars().try_fold(0i64, |value, c| {
+        let digit = match c {
+            '0'..='9' => c as i64 - '0' as i64,
+            'a'..='z' => c as i64 - 'a' as i64 + 10,
+            'A'..='Z' if base <= 36 => c as i64 - 'A' as i64 + 10,
+            'A'..='Z' => c as i64 - 'A' as i64 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return None,
+        };
+        (digit < base).then(|| value.wrapping_mul(base).wrapping_add(digit))
+    })
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c.is_ascii_digit() {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_alphanumeric() || "#@_".contains(chars[pos]))
+            {
+                pos += 1;
+            }
+            let text: String = chars[start..pos].iter().collect();
+            let number = parse_number(&text).ok_or(format!("{text}: invalid number"))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_') {
+                pos += 1;
+            }
+            tokens.push(Token::Name(chars[start..pos].iter().collect()));
+        } else {
+            let rest: String = chars[pos..].iter().take(3).collect();
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or(format!("syntax error: invalid arithmetic operator `{c}'"))?;
+            pos += op.len();
+            tokens.push(Token::Op(op));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Evaluator<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    state: &'a mut ShellState,
+    // Above zero inside the branch of `&&`, `||` or `?:` that is not taken,
+    // which is parsed but has no effects
+    skip: usize,
+    depth: usize,
+}
+
+impl Evaluator<'_> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn unexpected(&self) -> String {
+        match self.tokens.get(self.pos) {
+            Some(Token::Number(number)) => format!("syntax error: unexpected `{number}'"),
+            Some(Token::Name(name)) => format!("syntax error: unexpected `{name}'"),
+            Some(Token::Op(op)) => format!("syntax error: unexpected `{op}'"),
+            None => "syntax error: operand expected".to_string(),
+        }
+    }
+
+    fn evaluate_if(
+        &mut self,
+        active: bool,
+        parse: impl FnOnce(&mut Self) -> Result<i64, String>,
+    ) -> Result<i64, String> {
+        if !active {
+            self.skip += 1;
+        }
+        let value = parse(self);
+        if !active {
+            self.skip -= 1;
+        }
+        value
+    }
+
+    fn variable(&mut self, name: &str) -> Result<i64, String> {
+        if self.skip > 0 {
+            return Ok(0);
+        }
+        let value = self.state.lookup(name).unwrap_or_default();
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(0);
+        }
+        if let Some(number) = parse_number(value) {
+            return Ok(number);
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err("expression recursion level exceeded".to_string());
+        }
+        evaluate_nested(value, self.state, self.depth + 1).map_err(|e| e.message)
+    }
+
+    fn assign(&mut self, name: &str, value: i64) -> Result<(), String> {
+        if self.skip > 0 {
+            return Ok(());
+        }
+        self.state
+            .set_var(name, &value.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    fn apply(&self, op: &str, left: i64, right: i64) -> Result<i64, String> {
+        Ok(match op {
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => {
+                if self.skip > 0 {
+                    return Ok(0);
+                }
+                return Err("division by 0".to_string());
+            }
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            "**" if right < 0 => return Err("exponent less than 0".to_string()),
+            "**" => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "<" => (left < right) as i64,
+            "<=" => (left <= right) as i64,
+            ">" => (left > right) as i64,
+            ">=" => (left >= right) as i64,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            "&" => left & right,
+            "^" => left ^ right,
+            "|" => left | right,
+            "&&" => (left != 0 && right != 0) as i64,
+            "||" => (left != 0 || right != 0) as i64,
+            _ => return Err(format!("syntax error: invalid arithmetic operator `{op}'")),
+        })
+    }
+
+    fn comma(&mut self) -> Result<i64, String> {
+        let mut value = self.assignment()?;
+        while self.eat(",") {
+            value = self.assignment()?;
+        }
+        Ok(value)
+    }
+
+    fn assignment(&mut self) -> Result<i64, String> {
+        if let Some(Token::Name(name)) = self.tokens.get(self.pos)
+            && let Some(Token::Op(op)) = self.tokens.get(self.pos + 1)
+            && ASSIGNMENTS.contains(op)
+        {
+            let (name, op) = (name.clone(), *op);
+            self.pos += 2;
+            let value = self.assignment()?;
+            let value = match op.strip_suffix('=').filter(|op| !op.is_empty()) {
+                Some(op) => {
+                    let current = self.variable(&name)?;
+                    self.apply(op, current, value)?
+                }
+                None => value,
+            };
+            self.assign(&name, value)?;
+            return Ok(value);
+        }
+        self.conditional()
+    }
+
+    fn conditional(&mut self) -> Result<i64, String> {
+        let condition = self.binary(0)?;
+        if !self.eat("?") {
+            return Ok(condition);
+        }
+        let then = self.evaluate_if(condition != 0, Self::comma)?;
+        self.expect(":")?;
+        let otherwise = self.evaluate_if(condition == 0, Self::conditional)?;
+        Ok(if condition != 0 { then } else { otherwise })
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == LEVELS.len() {
+            return self.power();
+        }
+
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.peek_op().filter(|op| LEVELS[level].contains(op)) {
+            self.pos += 1;
+            let right = match op {
+                "&&" => self.evaluate_if(left != 0, |s| s.binary(level + 1))?,
+                "||" => self.evaluate_if(left == 0, |s| s.binary(level + 1))?,
+                _ => self.binary(level + 1)?,
+            };
+            left = self.apply(op, left, right)?;
+        }
+        Ok(left)
+    }
+
+    fn power(&mut self) -> Result<i64, String> {
+        let base = self.unary()?;
+        if self.eat("**") {
+            let exponent = self.power()?;
+            return self.apply("**", base, exponent);
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        match self.peek_op() {
+            Some(op @ ("++" | "--")) => {
+                self.pos += 1;
+                let Some(Token::Name(name)) = self.tokens.get(self.pos).cloned() else {
+                    return Err(self.unexpected());
+                };
+                self.pos += 1;
+                let step = if op == "++" { 1 } else { -1 };
+                let value = self.variable(&name)?.wrapping_add(step);
+                self.assign(&name, value)?;
+                Ok(value)
+            }
+            Some(op @ ("+" | "-" | "!" | "~")) => {
+                self.pos += 1;
+                let value = self.unary()?;
+                Ok(match op {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value,
+                })
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<i64, String> {
+        match self.tokens.get(self.pos).cloned() {
+            Some(Token::Number(number)) => {
+                self.pos += 1;
+                Ok(number)
+            }
+            Some(Token::Name(name)) => {
+                self.pos += 1;
+                let value = self.variable(&name)?;
+                if let Some(op @ ("++" | "--")) = self.peek_op() {
+                    self.pos += 1;
+                    let step = if op == "++" { 1 } else { -1 };
+                    self.assign(&name, value.wrapping_add(step))?;
+                }
+                Ok(value)
+            }
+            Some(Token::Op("(")) => {
+                self.pos += 1;
+                let value = self.comma()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+fn evaluate_nested(
+    expression: &str,
+    state: &mut ShellState,
+    depth: usize,
+) -> Result<i64, ArithError> {
+    let error = |message| ArithError {
+        expression: expression.to_string(),
+        message,
+    };
+
+    let tokens = tokenize(expression).map_err(error)?;
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+    let mut evaluator = Evaluator {
+        tokens,
+        pos: 0,
+        state,
+        skip: 0,
+        depth,
+    };
+    let value = evaluator.comma().map_err(error)?;
+    if evaluator.pos < evaluator.tokens.len() {
+        return Err(error(evaluator.unexpected()));
+    }
+    Ok(value)
+}
+
+// Evaluates an already expanded arithmetic expression, with the C operators
+// on 64-bit integers. Names are shell variables, which assignments change.
+pub fn evaluate(expression: &str, state: &mut ShellState) -> Result<i64, ArithError> {
+    evaluate_nested(expression, state, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> Result<i64, ArithError> {
+        evaluate(expression, &mut ShellState::new())
+    }
+
+    #[test]
+    fn precedence_and_grouping() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), 7);
+        assert_eq!(eval("(1 + 2) * 3").unwrap(), 9);
+        assert_eq!(eval("2 ** 3 ** 2").unwrap(), 512);
+        assert_eq!(eval("-7 / 2").unwrap(), -3);
+        assert_eq!(eval("-7 % 3").unwrap(), -1);
+        assert_eq!(eval("1 < 2 && 2 > 3 || 4 == 4").unwrap(), 1);
+        assert_eq!(eval("0 ? 1 : 2").unwrap(), 2);
+    }
+
+    #[test]
+    fn number_bases() {
+        assert_eq!(eval("0x1f").unwrap(), 31);
+        assert_eq!(eval("010").unwrap(), 8);
+        assert_eq!(eval("2#101").unwrap(), 5);
+    }
+
+    #[test]
+    fn assignment_updates_variables() {
+        let mut state = ShellState::new();
+        state.set_var("n", "5").unwrap();
+        assert_eq!(evaluate("n += 2", &mut state).unwrap(), 7);
+        assert_eq!(evaluate("n++", &mut state).unwrap(), 7);
+        assert_eq!(state.lookup("n").as_deref(), Some("8"));
+        assert_eq!(evaluate("m = n * 2", &mut state).unwrap(), 16);
+        assert_eq!(state.lookup("m").as_deref(), Some("16"));
+    }
+
+    #[test]
+    fn unset_variables_are_zero() {
+        assert_eq!(eval("unset_in_arith_test + 1").unwrap(), 1);
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("1 % 0").is_err());
+    }
+
+    #[test]
+    fn self_reference_is_an_error() {
+        let mut state = ShellState::new();
+        state.set_var("a", "a").unwrap();
+        let error = evaluate("a", &mut state).unwrap_err();
+        assert_eq!(error.message, "expression recursion level exceeded");
+    }
+
+    #[test]
+    fn mutual_reference_is_an_error() {
+        let mut state = ShellState::new();
+        state.set_var("a", "b + 1").unwrap();
+        state.set_var("b", "a + 1").unwrap();
+        assert!(evaluate("a", &mut state).is_err());
+    }
+}
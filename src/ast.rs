@@ -0,0 +1,314 @@
+use crate::lexer::RedirectOp;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Word {
+    pub raw: String,
+}
+
+impl Word {
+    pub fn new(raw: impl Into<String>) -> Word {
+        Word { raw: raw.into() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub fd: Option<i32>,
+    pub op: RedirectOp,
+    pub target: Word,
+    // The lines of a here-document, whose target is its delimiter
+    pub body: Option<String>,
+}
+
+// A `NAME=value` word in front of a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pub name: String,
+    pub value: Word,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimpleCommand {
+    pub assignments: Vec<Assignment>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaseArm {
+    pub patterns: Vec<Word>,
+    pub body: List,
+}
+
+// The expression inside `[[ ]]`. Operators are kept as written, such as
+// "-f" or "==".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CondExpr {
+    Word(Word),
+    Unary(String, Word),
+    Binary(Word, String, Word),
+    Not(Box<CondExpr>),
+    And(Box<CondExpr>, Box<CondExpr>),
+    Or(Box<CondExpr>, Box<CondExpr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompoundCommand {
+    Group(List),
+    If {
+        branches: Vec<(List, List)>,
+        else_body: Option<List>,
+    },
+    While {
+        condition: List,
+        body: List,
+        until: bool,
+    },
+    For {
+        variable: String,
+        words: Option<Vec<Word>>,
+        body: List,
+    },
+    Case {
+        word: Word,
+        arms: Vec<CaseArm>,
+    },
+    Arithmetic(Word),
+    Conditional(CondExpr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub body: CompoundCommand,
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Simple(SimpleCommand),
+    Compound(CompoundCommand, Vec<Redirect>),
+    Function(Rc<Function>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+}
+
+impl Pipeline {
+    // The command of a pipeline that is nothing but one simple command
+    pub fn simple(&self) -> Option<&SimpleCommand> {
+        match self.commands.as_slice() {
+            [Command::Simple(command)] => Some(command),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AndOrOp {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(AndOrOp, Pipeline)>,
+    pub background: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct List {
+    pub items: Vec<AndOr>,
+}
+
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(fd) = self.fd {
+            write!(f, "{fd}")?;
+        }
+        write!(f, "{}{}", self.op, self.target)
+    }
+}
+
+impl fmt::Display for Assignment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+impl fmt::Display for SimpleCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self
+            .assignments
+            .iter()
+            .map(|assignment| assignment.to_string())
+            .chain(self.words.iter().map(|word| word.to_string()))
+            .chain(self.redirects.iter().map(|redirect| redirect.to_string()))
+            .collect();
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl fmt::Display for CondExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Operands that bind more loosely than their operator need parentheses
+        let grouped = |expression: &CondExpr, loose: bool| {
+            if loose {
+                format!("( {expression} )")
+            } else {
+                expression.to_string()
+            }
+        };
+
+        match self {
+            CondExpr::Word(word) => write!(f, "{word}"),
+            CondExpr::Unary(op, word) => write!(f, "{op} {word}"),
+            CondExpr::Binary(left, op, right) => write!(f, "{left} {op} {right}"),
+            CondExpr::Not(expression) => {
+                let loose = matches!(**expression, CondExpr::And(..) | CondExpr::Or(..));
+                write!(f, "! {}", grouped(expression, loose))
+            }
+            CondExpr::And(left, right) => {
+                let left = grouped(left, matches!(**left, CondExpr::Or(..)));
+                let right = grouped(
+                    right,
+                    matches!(**right, CondExpr::And(..) | CondExpr::Or(..)),
+                );
+                write!(f, "{left} && {right}")
+            }
+            CondExpr::Or(left, right) => {
+                let right = grouped(right, matches!(**right, CondExpr::Or(..)));
+                write!(f, "{left} || {right}")
+            }
+        }
+    }
+}
+
+impl fmt::Display for CompoundCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompoundCommand::Group(body) => write!(f, "{{ {body}; }}"),
+            CompoundCommand::If {
+                branches,
+                else_body,
+            } => {
+                for (i, (condition, body)) in branches.iter().enumerate() {
+                    let keyword = if i == 0 { "if" } else { "elif" };
+                    write!(f, "{keyword} {condition}; then {body}; ")?;
+                }
+                if let Some(body) = else_body {
+                    write!(f, "else {body}; ")?;
+                }
+                write!(f, "fi")
+            }
+            CompoundCommand::While {
+                condition,
+                body,
+                until,
+            } => {
+                let keyword = if *until { "until" } else { "while" };
+                write!(f, "{keyword} {condition}; do {body}; done")
+            }
+            CompoundCommand::For {
+                variable,
+                words,
+                body,
+            } => {
+                write!(f, "for {variable}")?;
+                if let Some(words) = words {
+                    write!(f, " in")?;
+                    for word in words {
+                        write!(f, " {word}")?;
+                    }
+                }
+                write!(f, "; do {body}; done")
+            }
+            CompoundCommand::Case { word, arms } => {
+                write!(f, "case {word} in")?;
+                for arm in arms {
+                    let patterns: Vec<String> =
+                        arm.patterns.iter().map(|p| p.to_string()).collect();
+                    write!(f, " {}) {};;", patterns.join("|"), arm.body)?;
+                }
+                write!(f, " esac")
+            }
+            CompoundCommand::Arithmetic(expression) => write!(f, "(({expression}))"),
+            CompoundCommand::Conditional(expression) => write!(f, "[[ {expression} ]]"),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Simple(command) => write!(f, "{command}"),
+            Command::Compound(command, redirects) => {
+                write!(f, "{command}")?;
+                for redirect in redirects {
+                    write!(f, " {redirect}")?;
+                }
+                Ok(())
+            }
+            Command::Function(function) => write!(f, "{function}"),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}() {}", self.name, self.body)?;
+        for redirect in &self.redirects {
+            write!(f, " {redirect}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stages: Vec<String> = self.commands.iter().map(|c| c.to_string()).collect();
+        write!(f, "{}", stages.join(" | "))
+    }
+}
+
+impl fmt::Display for AndOr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.first)?;
+        for (op, pipeline) in &self.rest {
+            let op = match op {
+                AndOrOp::And => "&&",
+                AndOrOp::Or => "||",
+            };
+            write!(f, " {op} {pipeline}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for List {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{item}")?;
+            if item.background {
+                write!(f, " &")?;
+            } else if i + 1 < self.items.len() {
+                write!(f, ";")?;
+            }
+        }
+        Ok(())
+    }
+}
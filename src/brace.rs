@@ -0,0 +1,205 @@
+// Brace expansion, which runs on the raw text of a word before anything
+// else is expanded: `a{b,c}d` becomes `abd acd` and `{1..3}` becomes
+// `1 2 3`. Braces inside quotes or substitutions are left alone.
+
+// The index just past a quoted, escaped or substituted part of the word
+// that starts at `i`, or None when none starts there
+fn skip_quoted(chars: &[char], i: usize) -> Option<usize> {
+    let len = chars.len();
+    match chars[i] {
+        '\\' => Some((i + 2).min(len)),
+        '\'' => {
+            let end = chars[i + 1..].iter().position(|&c| c == '\'');
+            Some(end.map_or(len, |end| i + end + 2))
+        }
+        quote @ ('"' | '`') => {
+            let mut j = i + 1;
+            while j < len && chars[j] != quote {
+                if chars[j] == '\\' {
+                    j += 1;
+                }
+                j += 1;
+            }
+            Some((j + 1).min(len))
+        }
+        '$' if matches!(chars.get(i + 1), Some('(' | '{')) => {
+            let (open, close) = if chars[i + 1] == '(' {
+                ('(', ')')
+            } else {
+                ('{', '}')
+            };
+            let mut depth = 1;
+            let mut j = i + 2;
+            while j < len {
+                if let Some(end) = skip_quoted(chars, j) {
+                    j = end;
+                    continue;
+                }
+                if chars[j] == open {
+                    depth += 1;
+                } else if chars[j] == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(j + 1);
+                    }
+                }
+                j += 1;
+            }
+            Some(len)
+        }
+        _ => None,
+    }
+}
+
+// `{start..end}` or `{start..end..step}` over integers or single letters.
+// An integer with a leading zero pads every number to the same width.
+fn sequence(body: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = body.split("..").collect();
+    let (start, end, step) = match parts.as_slice() {
+        [start, end] => (*start, *end, 1),
+        [start, end, step] => (*start, *end, step.parse::<i64>().ok()?),
+        _ => return None,
+    };
+    let step = step.unsigned_abs().max(1) as i64;
+
+    let range = |first: i64, last: i64| {
+        let mut values = Vec::new();
+        let mut value = first;
+        while (first <= last && value <= last) || (first > last && value >= last) {
+            values.push(value);
+            value += if first <= last { step } else { -step };
+        }
+        values
+    };
+
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        let padded = [start, end].iter().any(|text| {
+            let digits = text.trim_start_matches(['-', '+']);
+            digits.len() > 1 && digits.starts_with('0')
+        });
+        let width = if padded {
+            start.len().max(end.len())
+        } else {
+            0
+        };
+        return Some(
+            range(first, last)
+                .into_iter()
+                .map(|value| format!("{value:0width$}"))
+                .collect(),
+        );
+    }
+
+    let letter = |text: &str| {
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => Some(c as i64),
+            _ => None,
+        }
+    };
+    let (first, last) = (letter(start)?, letter(end)?);
+    Some(
+        range(first, last)
+            .into_iter()
+            .filter_map(|value| char::from_u32(value as u32))
+            .map(String::from)
+            .collect(),
+    )
+}
+
+// The closing brace of the expression opened at `open` and the words it
+// stands for, or None when the braces are to be taken literally
+fn brace_items(chars: &[char], open: usize) -> Option<(usize, Vec<String>)> {
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    let mut close = None;
+    let mut j = open + 1;
+
+    while j < chars.len() {
+        if let Some(end) = skip_quoted(chars, j) {
+            j = end;
+            continue;
+        }
+        match chars[j] {
+            '{' => depth += 1,
+            '}' if depth == 0 => {
+                close = Some(j);
+                break;
+            }
+            '}' => depth -= 1,
+            ',' if depth == 0 => commas.push(j),
+            _ => {}
+        }
+        j += 1;
+    }
+
+    let close = close?;
+    if commas.is_empty() {
+        let body: String = chars[open + 1..close].iter().collect();
+        return Some((close, sequence(&body)?));
+    }
+
+    let mut items = Vec::new();
+    let mut start = open + 1;
+    for end in commas.into_iter().chain([close]) {
+        items.push(chars[start..end].iter().collect());
+        start = end + 1;
+    }
+    Some((close, items))
+}
+
+pub fn expand_braces(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if let Some(end) = skip_quoted(&chars, i) {
+            i = end;
+            continue;
+        }
+        if chars[i] == '{'
+            && let Some((close, items)) = brace_items(&chars, i)
+        {
+            let prefix: String = chars[..i].iter().collect();
+            let suffix: String = chars[close + 1..].iter().collect();
+            // Each result is expanded again for nested braces and any that
+            // follow
+            return items
+                .iter()
+                .flat_map(|item| expand_braces(&format!("{prefix}{item}{suffix}")))
+                .collect();
+        }
+        i += 1;
+    }
+
+    vec![word.to_string()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists() {
+        assert_eq!(expand_braces("a{b,c}d"), ["abd", "acd"]);
+        assert_eq!(expand_braces("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand_braces("x{a,{b,c}}"), ["xa", "xb", "xc"]);
+        assert_eq!(expand_braces("{,a}"), ["", "a"]);
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(expand_braces("{1..3}"), ["1", "2", "3"]);
+        assert_eq!(expand_braces("{3..1}"), ["3", "2", "1"]);
+        assert_eq!(expand_braces("{a..c}"), ["a", "b", "c"]);
+        assert_eq!(expand_braces("{0..10..5}"), ["0", "5", "10"]);
+    }
+
+    #[test]
+    fn words_without_expansions_are_kept() {
+        assert_eq!(expand_braces("{a}"), ["{a}"]);
+        assert_eq!(expand_braces("a{b"), ["a{b"]);
+        assert_eq!(expand_braces("\\{a,b}"), ["\\{a,b}"]);
+        assert_eq!(expand_braces("'{a,b}'"), ["'{a,b}'"]);
+    }
+}
@@ -0,0 +1,853 @@
+use crate::arith::evaluate;
+use crate::commands::{Flow, execute_command, execute_input};
+use crate::condition::test;
+use crate::parser::is_name;
+use crate::state::{Jump, ShellState, Variable};
+use colored::*;
+use std::path::PathBuf;
+
+// A command the shell runs itself. Every execution path looks builtins up
+// here, so they behave the same in the prompt, the rc file and pipelines.
+pub trait Builtin: Sync {
+    fn name(&self) -> &'static str;
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>>;
+}
+
+static BUILTINS: &[&dyn Builtin] = &[
+    &Bracket,
+    &Alias,
+    &Bg,
+    &Break,
+    &Cd,
+    &Continue,
+    &Disown,
+    &Edit,
+    &Exit,
+    &Export,
+    &Fg,
+    &Functions,
+    &HistoryBuiltin,
+    &Jobs,
+    &Let,
+    &Local,
+    &PathBuiltin,
+    &Readonly,
+    &Return,
+    &Set,
+    &Shopt,
+    &Test,
+    &Unset,
+    &Wait,
+];
+
+pub fn find(name: &str) -> Option<&'static dyn Builtin> {
+    BUILTINS
+        .iter()
+        .find(|builtin| builtin.name() == name)
+        .copied()
+}
+
+pub fn names() -> Vec<&'static str> {
+    BUILTINS.iter().map(|builtin| builtin.name()).collect()
+}
+
+struct Alias;
+
+impl Builtin for Alias {
+    fn name(&self) -> &'static str {
+        "alias"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        if args.is_empty() {
+            for (name, value) in state.aliases.iter() {
+                println!("alias {}=\"{}\"", name, value);
+            }
+        } else if args.len() == 1 && args[0].contains('=') {
+            let alias_def = args[0];
+            if let Some(eq_pos) = alias_def.find('=') {
+                let name = alias_def[..eq_pos].to_string();
+                let value = alias_def[eq_pos + 1..].trim_matches('"').to_string();
+                state.aliases.insert(name, value);
+            }
+        } else {
+            eprintln!("{}: Usage: alias [name=value]", "alias".red().bold());
+            return Ok(Flow::Status(2));
+        }
+        Ok(Flow::Status(0))
+    }
+}
+
+struct Cd;
+
+impl Builtin for Cd {
+    fn name(&self) -> &'static str {
+        "cd"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        let target_dir = if args.is_empty() {
+            dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"))
+        } else if args[0] == "-" {
+            if let Some(prev_dir) = state.previous_dir.as_ref() {
+                prev_dir.clone()
+            } else {
+                eprintln!("{}: -: No previous directory", "cd".red().bold());
+                return Ok(Flow::Status(1));
+            }
+        } else {
+            let path = args[0];
+            if path.starts_with("~") {
+                let home_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
+                if path == "~" {
+                    home_dir
+                } else {
+                    home_dir.join(&path[2..])
+                }
+            } else {
+                PathBuf::from(path)
+            }
+        };
+
+        if let Err(e) = state.change_dir(&target_dir) {
+            eprintln!("{}: {}: {}", "cd".red().bold(), target_dir.display(), e);
+            Ok(Flow::Status(1))
+        } else {
+            if !args.is_empty() && args[0] == "-" {
+                println!("{}", target_dir.display());
+            }
+            Ok(Flow::Status(0))
+        }
+    }
+}
+
+struct Edit;
+
+impl Builtin for Edit {
+    fn name(&self) -> &'static str {
+        "edit"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        let editor = state.lookup("EDITOR").unwrap_or_else(|| "vim".to_string());
+        let last_command = if args.is_empty() {
+            state.last_line.clone()
+        } else {
+            Some(args.join(" "))
+        };
+
+        if let Some(cmd) = last_command {
+            let temp_file_path = "/tmp/last_command";
+            std::fs::write(temp_file_path, cmd)?;
+            // The editor gets the terminal like any other foreground job
+            let status = execute_command(&editor, &[temp_file_path], state);
+            if status == 0 {
+                let edited_command = std::fs::read_to_string(temp_file_path)?;
+                match execute_input(edited_command.trim(), state) {
+                    Ok(flow) => Ok(flow),
+                    Err(e) => {
+                        eprintln!("{}: {e}", "Error".red().bold());
+                        Ok(Flow::Status(1))
+                    }
+                }
+            } else {
+                eprintln!(
+                    "{}: Editor exited with status: {}",
+                    "Warning".yellow().bold(),
+                    status
+                );
+                Ok(Flow::Status(status))
+            }
+        } else {
+            eprintln!("{}: No previous command to edit.", "Info".blue().bold());
+            Ok(Flow::Status(1))
+        }
+    }
+}
+
+struct Exit;
+
+impl Builtin for Exit {
+    fn name(&self) -> &'static str {
+        "exit"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        if let Some(arg) = args.first() {
+            match arg.parse::<i32>() {
+                Ok(status) => state.last_status = status & 0xff,
+                Err(_) => {
+                    eprintln!("{}: {arg}: numeric argument required", "exit".red().bold());
+                    state.last_status = 2;
+                }
+            }
+        }
+        Ok(Flow::Exit)
+    }
+}
+
+struct PathBuiltin;
+
+impl Builtin for PathBuiltin {
+    fn name(&self) -> &'static str {
+        "path"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        if args.is_empty() {
+            if let Some(path) = state.lookup("PATH") {
+                println!("{}", path);
+            } else {
+                println!();
+            }
+        } else if args.len() == 1 {
+            let new_path = args[0];
+            let expanded_path = if new_path.starts_with("~") {
+                let home_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
+                home_dir.join(&new_path[2..]).to_string_lossy().to_string()
+            } else {
+                new_path.to_string()
+            };
+
+            let path_buf = PathBuf::from(&expanded_path);
+            if !path_buf.exists() {
+                eprintln!(
+                    "{}: Directory does not exist: {}",
+                    "path".red().bold(),
+                    expanded_path
+                );
+                return Ok(Flow::Status(1));
+            } else if !path_buf.is_dir() {
+                eprintln!(
+                    "{}: Not a directory: {}",
+                    "path".red().bold(),
+                    expanded_path
+                );
+                return Ok(Flow::Status(1));
+            } else {
+                let current_path = state.lookup("PATH").unwrap_or_default();
+                let new_full_path = if current_path.is_empty() {
+                    expanded_path.clone()
+                } else {
+                    format!("{}:{}", expanded_path, current_path)
+                };
+                if let Err(e) = state.set_var("PATH", &new_full_path) {
+                    eprintln!("{}: {e}", "path".red().bold());
+                    return Ok(Flow::Status(1));
+                }
+                println!("{}: Added {} to PATH", "path".green().bold(), expanded_path);
+            }
+        } else {
+            eprintln!("{}: Usage: path [directory]", "path".red().bold());
+            return Ok(Flow::Status(2));
+        }
+        Ok(Flow::Status(0))
+    }
+}
+
+struct Set;
+
+impl Builtin for Set {
+    fn name(&self) -> &'static str {
+        "set"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        let assigned = if args.is_empty() {
+            let mut variables: Vec<_> = state.variables.iter().collect();
+            variables.sort_by(|a, b| a.0.cmp(b.0));
+            for (key, variable) in variables {
+                if let Some(value) = &variable.value {
+                    println!("{}={}", key, value);
+                }
+            }
+            Ok(())
+        } else if args.len() == 1 && args[0].contains('=') {
+            let (name, value) = args[0].split_once('=').unwrap_or_default();
+            state.set_var(name, value)
+        } else if args.len() == 2 {
+            state.set_var(args[0], args[1])
+        } else {
+            eprintln!(
+                "{}: Usage: set [VAR=value] or set [VAR] [value]",
+                "set".red().bold()
+            );
+            return Ok(Flow::Status(2));
+        };
+
+        if let Err(e) = assigned {
+            eprintln!("{}: {e}", "set".red().bold());
+            return Ok(Flow::Status(1));
+        }
+        Ok(Flow::Status(0))
+    }
+}
+
+// Splits a `NAME[=value]` argument, reporting an invalid name
+fn parse_declaration<'a>(command: &str, arg: &'a str) -> Option<(&'a str, Option<&'a str>)> {
+    let (name, value) = match arg.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (arg, None),
+    };
+    if !is_name(name) {
+        eprintln!("{}: {arg}: not a valid identifier", command.red().bold());
+        return None;
+    }
+    Some((name, value))
+}
+
+fn print_declarations(command: &str, state: &ShellState, selected: fn(&Variable) -> bool) {
+    let mut variables: Vec<_> = state
+        .variables
+        .iter()
+        .filter(|(_, variable)| selected(variable))
+        .collect();
+    variables.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, variable) in variables {
+        match &variable.value {
+            Some(value) => println!("{command} {name}=\"{value}\""),
+            None => println!("{command} {name}"),
+        }
+    }
+}
+
+// Gives each name its value, if there is one, and then `attribute`
+fn declare(
+    command: &str,
+    args: &[&str],
+    state: &mut ShellState,
+    attribute: impl Fn(&mut Variable),
+) -> i32 {
+    let mut status = 0;
+    for arg in args {
+        let Some((name, value)) = parse_declaration(command, arg) else {
+            status = 1;
+            continue;
+        };
+        if let Some(value) = value
+            && let Err(e) = state.set_var(name, value)
+        {
+            eprintln!("{}: {e}", command.red().bold());
+            status = 1;
+            continue;
+        }
+        attribute(state.variables.entry(name.to_string()).or_default());
+    }
+    status
+}
+
+struct Export;
+
+impl Builtin for Export {
+    fn name(&self) -> &'static str {
+        "export"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        let (exported, names) = match args.split_first() {
+            Some((&"-n", names)) => (false, names),
+            Some((&"-p", names)) => (true, names),
+            _ => (true, args),
+        };
+
+        if names.is_empty() {
+            print_declarations(self.name(), state, |variable| variable.exported);
+            return Ok(Flow::Status(0));
+        }
+
+        let status = declare(self.name(), names, state, |variable| {
+            variable.exported = exported
+        });
+        Ok(Flow::Status(status))
+    }
+}
+
+struct Readonly;
+
+impl Builtin for Readonly {
+    fn name(&self) -> &'static str {
+        "readonly"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        let names = match args.split_first() {
+            Some((&"-p", names)) => names,
+            _ => args,
+        };
+
+        if names.is_empty() {
+            print_declarations(self.name(), state, |variable| variable.readonly);
+            return Ok(Flow::Status(0));
+        }
+
+        let status = declare(self.name(), names, state, |variable| {
+            variable.readonly = true
+        });
+        Ok(Flow::Status(status))
+    }
+}
+
+struct Test;
+
+impl Builtin for Test {
+    fn name(&self) -> &'static str {
+        "test"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        Ok(Flow::Status(test_status("test", args, state)))
+    }
+}
+
+// `[`, which is `test` with a closing `]`
+struct Bracket;
+
+impl Builtin for Bracket {
+    fn name(&self) -> &'static str {
+        "["
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        let Some((&"]", args)) = args.split_last() else {
+            eprintln!("{}: missing `]'", "[".red().bold());
+            return Ok(Flow::Status(2));
+        };
+        Ok(Flow::Status(test_status("[", args, state)))
+    }
+}
+
+fn test_status(command: &str, args: &[&str], state: &ShellState) -> i32 {
+    match test(args, state) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("{}: {e}", command.red().bold());
+            2
+        }
+    }
+}
+
+struct Unset;
+
+impl Builtin for Unset {
+    fn name(&self) -> &'static str {
+        "unset"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        let (functions, names) = match args.split_first() {
+            Some((&"-f", names)) => (true, names),
+            Some((&"-v", names)) => (false, names),
+            _ => (false, args),
+        };
+
+        let mut status = 0;
+        for name in names {
+            if functions {
+                state.functions.remove(*name);
+            } else if let Err(e) = state.unset_var(name) {
+                eprintln!("{}: {e}", self.name().red().bold());
+                status = 1;
+            }
+        }
+        Ok(Flow::Status(status))
+    }
+}
+
+struct Shopt;
+
+impl Builtin for Shopt {
+    fn name(&self) -> &'static str {
+        "shopt"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        let (value, names) = match args.split_first() {
+            Some((&"-s", names)) => (Some(true), names),
+            Some((&"-u", names)) => (Some(false), names),
+            _ => (None, args),
+        };
+
+        let mut status = 0;
+        for name in names {
+            let known = match value {
+                Some(value) => state.options.set(name, value),
+                None => state
+                    .options
+                    .names()
+                    .iter()
+                    .any(|(option, _)| option == name),
+            };
+            if !known {
+                eprintln!("{}: {name}: Invalid option name", "shopt".red().bold());
+                status = 1;
+            }
+        }
+
+        if value.is_none() {
+            for (name, enabled) in state.options.names() {
+                if names.is_empty() || names.contains(&name) {
+                    println!("{name:<16}{}", if enabled { "on" } else { "off" });
+                }
+            }
+        }
+        Ok(Flow::Status(status))
+    }
+}
+
+fn loop_control(command: &str, args: &[&str], state: &mut ShellState) -> i32 {
+    let count = match args.first().map(|arg| arg.parse::<usize>()) {
+        None => 1,
+        Some(Ok(count)) if count > 0 => count,
+        _ => {
+            eprintln!(
+                "{}: {}: loop count out of range",
+                command.red().bold(),
+                args[0]
+            );
+            return 1;
+        }
+    };
+
+    if state.loop_depth == 0 {
+        eprintln!("{}: only meaningful in a loop", command.red().bold());
+        return 0;
+    }
+
+    let count = count.min(state.loop_depth);
+    state.jump = Some(if command == "break" {
+        Jump::Break(count)
+    } else {
+        Jump::Continue(count)
+    });
+    0
+}
+
+struct Break;
+
+impl Builtin for Break {
+    fn name(&self) -> &'static str {
+        "break"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        Ok(Flow::Status(loop_control(self.name(), args, state)))
+    }
+}
+
+struct Continue;
+
+impl Builtin for Continue {
+    fn name(&self) -> &'static str {
+        "continue"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        Ok(Flow::Status(loop_control(self.name(), args, state)))
+    }
+}
+
+struct Return;
+
+impl Builtin for Return {
+    fn name(&self) -> &'static str {
+        "return"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        if state.local_scopes.is_empty() {
+            eprintln!("{}: can only return from a function", "return".red().bold());
+            return Ok(Flow::Status(1));
+        }
+
+        let status = match args.first() {
+            None => state.last_status,
+            Some(arg) => match arg.parse::<i32>() {
+                Ok(status) => status & 0xff,
+                Err(_) => {
+                    eprintln!(
+                        "{}: {arg}: numeric argument required",
+                        "return".red().bold()
+                    );
+                    2
+                }
+            },
+        };
+        state.jump = Some(Jump::Return);
+        Ok(Flow::Status(status))
+    }
+}
+
+struct Let;
+
+impl Builtin for Let {
+    fn name(&self) -> &'static str {
+        "let"
+    }
+
+    // Each argument is an expression; the status is 0 if the last one is
+    // not zero
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        if args.is_empty() {
+            eprintln!("{}: expression expected", "let".red().bold());
+            return Ok(Flow::Status(1));
+        }
+
+        let mut value = 0;
+        for arg in args {
+            match evaluate(arg, state) {
+                Ok(result) => value = result,
+                Err(e) => {
+                    eprintln!("{}: {e}", "let".red().bold());
+                    return Ok(Flow::Status(1));
+                }
+            }
+        }
+        Ok(Flow::Status((value == 0) as i32))
+    }
+}
+
+struct Local;
+
+impl Builtin for Local {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        if state.local_scopes.is_empty() {
+            eprintln!("{}: can only be used in a function", "local".red().bold());
+            return Ok(Flow::Status(1));
+        }
+
+        let mut status = 0;
+        for arg in args {
+            let Some((name, value)) = parse_declaration(self.name(), arg) else {
+                status = 1;
+                continue;
+            };
+
+            let previous = state.variables.get(name).cloned();
+            if previous.as_ref().is_some_and(|variable| variable.readonly) {
+                eprintln!("{}: {name}: readonly variable", "local".red().bold());
+                status = 1;
+                continue;
+            }
+            if let Some(scope) = state.local_scopes.last_mut()
+                && !scope.iter().any(|(saved, _)| saved == name)
+            {
+                scope.push((name.to_string(), previous.clone()));
+            }
+
+            // The local copy is passed on to children if the outer one was
+            let variable = Variable {
+                value: value.map(|value| value.to_string()),
+                exported: previous.is_some_and(|variable| variable.exported),
+                readonly: false,
+            };
+            state.variables.insert(name.to_string(), variable);
+        }
+        Ok(Flow::Status(status))
+    }
+}
+
+struct Functions;
+
+impl Builtin for Functions {
+    fn name(&self) -> &'static str {
+        "functions"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        let mut names: Vec<&String> = state.functions.keys().collect();
+        names.sort();
+
+        let mut status = 0;
+        for name in args {
+            if !state.functions.contains_key(*name) {
+                eprintln!("{}: {name}: not a function", "functions".red().bold());
+                status = 1;
+            }
+        }
+
+        for name in names {
+            if args.is_empty() || args.contains(&name.as_str()) {
+                println!("{}", state.functions[name]);
+            }
+        }
+        Ok(Flow::Status(status))
+    }
+}
+
+struct HistoryBuiltin;
+
+impl Builtin for HistoryBuiltin {
+    fn name(&self) -> &'static str {
+        "history"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        Ok(Flow::Status(state.history.list(args, &state.cwd)))
+    }
+}
+
+struct Jobs;
+
+impl Builtin for Jobs {
+    fn name(&self) -> &'static str {
+        "jobs"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        Ok(Flow::Status(state.jobs.list(args)))
+    }
+}
+
+struct Fg;
+
+impl Builtin for Fg {
+    fn name(&self) -> &'static str {
+        "fg"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        Ok(Flow::Status(state.jobs.foreground(args)))
+    }
+}
+
+struct Bg;
+
+impl Builtin for Bg {
+    fn name(&self) -> &'static str {
+        "bg"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        Ok(Flow::Status(state.jobs.background(args)))
+    }
+}
+
+struct Wait;
+
+impl Builtin for Wait {
+    fn name(&self) -> &'static str {
+        "wait"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        Ok(Flow::Status(state.jobs.wait(args)))
+    }
+}
+
+struct Disown;
+
+impl Builtin for Disown {
+    fn name(&self) -> &'static str {
+        "disown"
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        state: &mut ShellState,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        Ok(Flow::Status(state.jobs.disown(args)))
+    }
+}
@@ -1,467 +1,515 @@
-use crate::parser::parse_arguments;
+use crate::ast::{AndOr, AndOrOp, Assignment, Command, Pipeline};
+use crate::builtins;
+use crate::control::{call_function, run_pipeline};
+use crate::expand::{expand_word, expand_words};
+use crate::jobs::{Job, Subshell};
+use crate::parser::Parser;
+use crate::redirect::RedirectGuard;
+use crate::state::{Jump, SavedVariables, ShellState};
 use colored::*;
-use rustyline::{Editor, history::FileHistory};
-use std::collections::HashMap;
-use std::env;
-use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::sync::{Mutex, OnceLock};
+use std::io::{self, PipeReader, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Stdio};
+use std::thread;
 
-static PREVIOUS_DIR: OnceLock<Mutex<Option<PathBuf>>> = OnceLock::new();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Status(i32),
+    Exit,
+}
 
-pub fn execute_command(command: &str, args: &[&str]) {
-    let mut cmd = Command::new(command);
-    cmd.args(args);
-    cmd.stdout(Stdio::inherit());
-    cmd.stderr(Stdio::inherit());
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
 
-    let mut child = match cmd.spawn() {
-        Ok(child) => child,
+fn spawn_error_code(e: &io::Error) -> i32 {
+    match e.kind() {
+        io::ErrorKind::NotFound => 127,
+        _ => 126,
+    }
+}
+
+pub fn execute_and_or(
+    and_or: &AndOr,
+    state: &mut ShellState,
+) -> Result<Flow, Box<dyn std::error::Error>> {
+    if and_or.background {
+        return Ok(Flow::Status(execute_background(and_or, state)));
+    }
+    execute_foreground(and_or, state)
+}
+
+// Runs `body` in a forked copy of the shell and returns the child's pid to
+// the parent. The child exits with the status `body` returns.
+pub fn fork_subshell(
+    state: &mut ShellState,
+    kind: Subshell,
+    body: impl FnOnce(&mut ShellState) -> i32,
+) -> Option<i32> {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        eprintln!(
+            "{}: Failed to start subshell: {}",
+            "Error".red().bold(),
+            io::Error::last_os_error()
+        );
+        return None;
+    }
+
+    if pid == 0 {
+        state.jobs.enter_subshell(kind);
+        state.in_subshell = true;
+        let status = body(state);
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        unsafe { libc::_exit(status) };
+    }
+
+    Some(pid)
+}
+
+fn flow_status(result: Result<Flow, Box<dyn std::error::Error>>, state: &ShellState) -> i32 {
+    match result {
+        Ok(Flow::Status(status)) => status,
+        Ok(Flow::Exit) => state.last_status,
         Err(e) => {
-            eprintln!("{}: {command}: {e}", "Error".red().bold());
-            return;
+            eprintln!("{}: {e}", "Error".red().bold());
+            1
         }
+    }
+}
+
+// Background lists run in a forked copy of the shell, so builtins and
+// && / || chains behave exactly as they would in the foreground.
+fn execute_background(and_or: &AndOr, state: &mut ShellState) -> i32 {
+    let Some(pid) = fork_subshell(state, Subshell::Background, |state| {
+        let result = execute_foreground(and_or, state);
+        flow_status(result, state)
+    }) else {
+        return 1;
     };
 
-    let status = child.wait();
+    let mut job = Job::new(and_or.to_string());
+    job.add_process(pid);
+    state.jobs.place_process(pid, &job);
+    state.last_background_pid = Some(pid as u32);
 
-    match status {
-        Ok(status) => {
-            if !status.success() {
-                eprintln!(
-                    "{}: Command exited with status: {status}",
-                    "Warning".yellow().bold()
-                );
-            }
-        }
-        Err(e) => {
-            eprintln!("{}: Failed to wait for command: {e}", "Error".red().bold());
-        }
+    let id = state.jobs.add(job);
+    if state.jobs.is_enabled() {
+        eprintln!("[{id}] {pid}");
     }
+    0
 }
 
-pub fn execute_single_command(
-    command: &str,
-    args: &[&str],
-    aliases: &HashMap<String, String>,
-    allow_pipes: bool,
-    full_input: &str,
-) {
-    match command {
-        "set" => {
-            if args.is_empty() {
-                for (key, value) in env::vars() {
-                    println!("{}={}", key, value);
-                }
-            } else if args.len() == 1 && args[0].contains('=') {
-                let env_def = args[0];
-                if let Some(eq_pos) = env_def.find('=') {
-                    let name = &env_def[..eq_pos];
-                    let value = &env_def[eq_pos + 1..];
-                    unsafe {
-                        env::set_var(name, value);
-                    }
-                }
-            } else if args.len() == 2 {
-                unsafe {
-                    env::set_var(args[0], args[1]);
+fn execute_foreground(
+    and_or: &AndOr,
+    state: &mut ShellState,
+) -> Result<Flow, Box<dyn std::error::Error>> {
+    let pipelines = std::iter::once((None, &and_or.first)).chain(
+        and_or
+            .rest
+            .iter()
+            .map(|(op, pipeline)| (Some(*op), pipeline)),
+    );
+
+    let mut flow = Flow::Status(0);
+    let mut ran_last = false;
+    let mut last_compound = false;
+
+    for (op, pipeline) in pipelines {
+        let Flow::Status(status) = flow else {
+            break;
+        };
+
+        ran_last = match op {
+            None => true,
+            Some(AndOrOp::And) => status == 0,
+            Some(AndOrOp::Or) => status != 0,
+        };
+        if ran_last {
+            last_compound = match pipeline.simple() {
+                Some(stage) => stage
+                    .words
+                    .first()
+                    .is_some_and(|word| state.functions.contains_key(&word.raw)),
+                None => pipeline.commands.len() == 1,
+            };
+            flow = run_pipeline(pipeline, state)?;
+            if let Flow::Status(status) = flow {
+                state.last_status = status;
+                // An interrupt abandons the rest of the list
+                if status == 128 + libc::SIGINT {
+                    break;
                 }
-            } else {
-                eprintln!(
-                    "{}: Usage: set [VAR=value] or set [VAR] [value]",
-                    "set".red().bold()
-                );
             }
-        }
-        "alias" => {
-            if args.is_empty() {
-                for (name, value) in aliases.iter() {
-                    println!("alias {}=\"{}\"", name, value);
-                }
-            } else if args.len() == 1 && args[0].contains('=') {
-                eprintln!(
-                    "{}: Cannot modify aliases in this context",
-                    "alias".yellow().bold()
-                );
-            } else {
-                eprintln!("{}: Usage: alias [name=value]", "alias".red().bold());
+            if state.jump.is_some() {
+                break;
             }
         }
-        "cd" => {
-            let current_dir = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
-
-            let target_dir = if args.is_empty() {
-                dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"))
-            } else if args[0] == "-" {
-                let prev_dir_mutex = PREVIOUS_DIR.get_or_init(|| Mutex::new(None));
-                if let Ok(prev_dir_guard) = prev_dir_mutex.lock() {
-                    if let Some(prev_dir) = prev_dir_guard.as_ref() {
-                        prev_dir.clone()
-                    } else {
-                        eprintln!("{}: -: No previous directory", "cd".red().bold());
-                        return;
-                    }
-                } else {
-                    eprintln!(
-                        "{}: -: Failed to access previous directory",
-                        "cd".red().bold()
-                    );
-                    return;
-                }
-            } else {
-                let path = args[0];
-                if path.starts_with("~") {
-                    let home_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
-                    if path == "~" {
-                        home_dir
-                    } else {
-                        home_dir.join(&path[2..])
-                    }
-                } else {
-                    PathBuf::from(path)
-                }
-            };
+    }
 
-            if let Err(e) = env::set_current_dir(&target_dir) {
-                eprintln!("{}: {}: {}", "cd".red().bold(), target_dir.display(), e);
-            } else {
-                let prev_dir_mutex = PREVIOUS_DIR.get_or_init(|| Mutex::new(None));
-                if let Ok(mut prev_dir_guard) = prev_dir_mutex.lock() {
-                    *prev_dir_guard = Some(current_dir);
-                }
+    // Failures are only reported at the prompt
+    if let Flow::Status(status) = flow
+        && state.interactive
+        && !state.in_subshell
+        // A condition has already acted on the failure
+        && !state.in_condition
+        // Compound commands and functions report their own failures
+        && !last_compound
+        && state.jump.is_none()
+        && status != 0
+        // The job table has already shown stopped and interrupted jobs
+        && status != 128 + libc::SIGTSTP
+        && status != 128 + libc::SIGINT
+        && status != 128 + libc::SIGQUIT
+        // && or || has already acted on a failure that skipped the rest
+        && ran_last
+    {
+        eprintln!(
+            "{}: Command exited with status: {status}",
+            "Warning".yellow().bold()
+        );
+    }
 
-                if !args.is_empty() && args[0] == "-" {
-                    println!("{}", target_dir.display());
-                }
-            }
+    Ok(flow)
+}
+
+// Runs shell code in a forked copy of the shell and returns what it wrote
+// to stdout, minus trailing newlines.
+pub fn capture_output(input: &str, state: &mut ShellState) -> String {
+    let (mut reader, writer) = match io::pipe() {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            eprintln!("{}: Failed to create pipe: {e}", "Error".red().bold());
+            state.last_status = 1;
+            return String::new();
         }
-        _ => {
-            let expanded_command = if let Some(alias_value) = aliases.get(command) {
-                alias_value.clone()
-            } else {
-                command.to_string()
-            };
+    };
 
-            if allow_pipes && full_input.contains('|') {
-                let pipe_parts: Vec<&str> = full_input.split('|').collect();
-                let commands: Vec<Vec<String>> = pipe_parts
-                    .iter()
-                    .map(|part| {
-                        let mut parsed = parse_arguments(part.trim());
-                        if !parsed.is_empty() {
-                            if let Some(alias_value) = aliases.get(&parsed[0]) {
-                                let alias_parts = parse_arguments(alias_value);
-                                parsed.splice(0..1, alias_parts);
-                            }
-                        }
-                        parsed
-                    })
-                    .collect();
-                execute_piped_commands(commands);
-            } else if expanded_command != command {
-                let expanded_parts = parse_arguments(&expanded_command);
-                let mut final_args = expanded_parts.clone();
-                final_args
-                    .extend_from_slice(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>());
-                let final_command = &final_args[0];
-                let final_arg_refs: Vec<&str> =
-                    final_args[1..].iter().map(|s| s.as_str()).collect();
-                execute_command(final_command, &final_arg_refs);
-            } else {
-                execute_command(command, args);
+    let pid = fork_subshell(state, Subshell::Substitution, |state| {
+        unsafe { libc::dup2(writer.as_raw_fd(), 1) };
+        match execute_input(input, state) {
+            Ok(flow) => flow_status(Ok(flow), state),
+            Err(e) => {
+                eprintln!("{}: {e}", "Error".red().bold());
+                2
             }
         }
-    }
-}
+    });
+    let Some(pid) = pid else {
+        state.last_status = 1;
+        return String::new();
+    };
 
-pub fn execute_piped_commands(commands: Vec<Vec<String>>) {
-    if commands.is_empty() {
-        return;
-    }
+    // Read on another thread so a large output cannot fill the pipe while
+    // this thread waits for the child
+    drop(writer);
+    let output = thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = reader.read_to_end(&mut output);
+        output
+    });
 
-    if commands.len() == 1 {
-        let cmd = &commands[0];
-        if !cmd.is_empty() {
-            let cmd_args: Vec<&str> = cmd[1..].iter().map(|s| s.as_str()).collect();
-            execute_command(&cmd[0], &cmd_args);
+    let mut status = 0;
+    while unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            break;
         }
-        return;
     }
+    state.last_status = exit_code(ExitStatus::from_raw(status));
 
-    let mut children = Vec::new();
-    let mut previous_stdout = None;
+    let output = output.join().unwrap_or_default();
+    let output = String::from_utf8_lossy(&output);
+    output.trim_end_matches('\n').to_string()
+}
 
-    for (i, cmd_parts) in commands.iter().enumerate() {
-        if cmd_parts.is_empty() {
-            continue;
+pub fn execute_command(command: &str, args: &[&str], state: &mut ShellState) -> i32 {
+    let mut cmd = state.command(command);
+    cmd.args(args);
+    cmd.stdout(Stdio::inherit());
+    cmd.stderr(Stdio::inherit());
+    state.jobs.configure_command(&mut cmd, 0, true);
+
+    let child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("{}: {command}: {e}", "Error".red().bold());
+            return spawn_error_code(&e);
         }
+    };
 
-        let command = &cmd_parts[0];
-        let args: Vec<&str> = cmd_parts[1..].iter().map(|s| s.as_str()).collect();
+    let mut text = vec![command];
+    text.extend_from_slice(args);
+    let mut job = Job::new(text.join(" "));
+    job.add_process(child.id() as i32);
+    state.jobs.place_process(child.id() as i32, &job);
 
-        let mut cmd = Command::new(command);
-        cmd.args(args);
+    state.jobs.wait_foreground(job)
+}
 
-        if let Some(stdout) = previous_stdout.take() {
-            cmd.stdin(stdout);
-        }
+pub fn execute_piped_commands(pipeline: &Pipeline, state: &mut ShellState) -> i32 {
+    let mut job = Job::new(pipeline.to_string());
+    let mut last_spawned = false;
+    let mut previous_stdout: Option<PipeReader> = None;
+    let last = pipeline.commands.len().saturating_sub(1);
+    let mut status = 0;
+
+    for (i, stage) in pipeline.commands.iter().enumerate() {
+        let parts = match stage {
+            Command::Simple(stage) => Some(expand_words(&stage.words, state)),
+            _ => None,
+        };
 
-        if i == commands.len() - 1 {
-            cmd.stdout(Stdio::inherit());
+        let (reader, writer) = if i < last {
+            match io::pipe() {
+                Ok((reader, writer)) => (Some(reader), Some(writer)),
+                Err(e) => {
+                    eprintln!("{}: Failed to create pipe: {e}", "Error".red().bold());
+                    status = 1;
+                    break;
+                }
+            }
         } else {
-            cmd.stdout(Stdio::piped());
+            (None, None)
+        };
+
+        let mut guard = RedirectGuard::new();
+        let stdin = previous_stdout.take();
+        let piped = stdin
+            .as_ref()
+            .map_or(Ok(()), |stdin| guard.redirect(stdin.as_raw_fd(), 0))
+            .and_then(|_| {
+                writer
+                    .as_ref()
+                    .map_or(Ok(()), |writer| guard.redirect(writer.as_raw_fd(), 1))
+            });
+        if let Err(e) = piped {
+            eprintln!("{}: Failed to connect pipe: {e}", "Error".red().bold());
+            status = 1;
+            break;
         }
+        previous_stdout = reader;
+
+        let (stage, parts) = match (stage, parts) {
+            (Command::Simple(stage), Some(parts)) => (stage, parts),
+            (stage, _) => {
+                // Compound commands run in a copy of the shell that joins the job
+                let single = Pipeline {
+                    commands: vec![stage.clone()],
+                };
+                let pid = fork_subshell(state, Subshell::Stage(job.pgid), |state| {
+                    let result = run_pipeline(&single, state);
+                    flow_status(result, state)
+                });
+                match pid {
+                    Some(pid) => {
+                        job.add_process(pid);
+                        state.jobs.place_process(pid, &job);
+                        last_spawned = i == last;
+                    }
+                    None => status = 1,
+                }
+                continue;
+            }
+        };
 
-        cmd.stderr(Stdio::inherit());
+        if let Err(e) = guard.apply(&stage.redirects, state) {
+            eprintln!("{}: {e}", "Error".red().bold());
+            status = 1;
+            continue;
+        }
 
-        match cmd.spawn() {
-            Ok(mut child) => {
-                previous_stdout = child.stdout.take();
-                children.push(child);
+        let parts = match parts {
+            Ok(parts) => parts,
+            Err(e) => {
+                eprintln!("{}: {e}", "Error".red().bold());
+                status = 1;
+                continue;
             }
+        };
+        let Some((command, args)) = parts.split_first() else {
+            status = 0;
+            continue;
+        };
+        let saved = match apply_assignments(&stage.assignments, state) {
+            Ok(saved) => saved,
             Err(e) => {
-                eprintln!("{}: {command}: {e}", "Error".red().bold());
-                return;
+                eprintln!("{}: {e}", "Error".red().bold());
+                status = 1;
+                continue;
             }
-        }
-    }
+        };
 
-    for mut child in children {
-        match child.wait() {
-            Ok(status) => {
-                if !status.success() {
-                    eprintln!(
-                        "{}: Command exited with status: {status}",
-                        "Warning".yellow().bold()
-                    );
+        // Functions and builtins run in a copy of the shell that joins the job
+        if state.functions.contains_key(command) || builtins::find(command).is_some() {
+            let pid = fork_subshell(state, Subshell::Stage(job.pgid), |state| {
+                let result = execute_simple(command, args, state);
+                flow_status(result, state)
+            });
+            state.restore_variables(saved);
+            match pid {
+                Some(pid) => {
+                    job.add_process(pid);
+                    state.jobs.place_process(pid, &job);
+                    last_spawned = i == last;
                 }
+                None => status = 1,
+            }
+            continue;
+        }
+
+        let mut cmd = state.command(command);
+        state.restore_variables(saved);
+        cmd.args(args);
+        state.jobs.configure_command(&mut cmd, job.pgid, true);
+
+        match cmd.spawn() {
+            Ok(child) => {
+                let pid = child.id() as i32;
+                job.add_process(pid);
+                state.jobs.place_process(pid, &job);
+                last_spawned = i == last;
             }
             Err(e) => {
-                eprintln!("{}: Failed to wait for command: {e}", "Error".red().bold());
+                eprintln!("{}: {command}: {e}", "Error".red().bold());
+                status = spawn_error_code(&e);
             }
         }
     }
+
+    if job.processes.is_empty() {
+        return status;
+    }
+
+    let job_status = state.jobs.wait_foreground(job);
+    if last_spawned { job_status } else { status }
 }
 
-pub fn handle_builtin_command(
-    command: &str,
-    args: &[&str],
-    rl: &mut Editor<crate::completion::ShellHelper, FileHistory>,
-    aliases: &mut HashMap<String, String>,
-) -> Result<Option<bool>, Box<dyn std::error::Error>> {
-    match command {
-        "exit" => Ok(Some(false)),
-        "alias" => {
-            if args.is_empty() {
-                for (name, value) in aliases.iter() {
-                    println!("alias {}=\"{}\"", name, value);
-                }
-            } else if args.len() == 1 && args[0].contains('=') {
-                let alias_def = args[0];
-                if let Some(eq_pos) = alias_def.find('=') {
-                    let name = alias_def[..eq_pos].to_string();
-                    let value = alias_def[eq_pos + 1..].trim_matches('"').to_string();
-                    aliases.insert(name, value);
-                }
-            } else {
-                eprintln!("{}: Usage: alias [name=value]", "alias".red().bold());
-            }
-            Ok(Some(true))
+// Gives the command about to run its `NAME=value` prefixes, exported, and
+// returns the variables to put back once it has been started
+fn apply_assignments(
+    assignments: &[Assignment],
+    state: &mut ShellState,
+) -> Result<SavedVariables, Box<dyn std::error::Error>> {
+    let mut saved = Vec::new();
+    for assignment in assignments {
+        let previous = state.variables.get(&assignment.name).cloned();
+        let assigned = expand_word(&assignment.value, state)
+            .map_err(Box::<dyn std::error::Error>::from)
+            .and_then(|value| Ok(state.set_var(&assignment.name, &value)?));
+        if let Err(e) = assigned {
+            state.restore_variables(saved);
+            return Err(e);
         }
-        "path" => {
-            if args.is_empty() {
-                if let Ok(path) = env::var("PATH") {
-                    println!("{}", path);
-                } else {
-                    println!();
-                }
-            } else if args.len() == 1 {
-                let new_path = args[0];
-                let expanded_path = if new_path.starts_with("~") {
-                    let home_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
-                    home_dir.join(&new_path[2..]).to_string_lossy().to_string()
-                } else {
-                    new_path.to_string()
-                };
-
-                let path_buf = PathBuf::from(&expanded_path);
-                if !path_buf.exists() {
-                    eprintln!(
-                        "{}: Directory does not exist: {}",
-                        "path".red().bold(),
-                        expanded_path
-                    );
-                } else if !path_buf.is_dir() {
-                    eprintln!(
-                        "{}: Not a directory: {}",
-                        "path".red().bold(),
-                        expanded_path
-                    );
-                } else {
-                    let current_path = env::var("PATH").unwrap_or_default();
-                    let new_full_path = if current_path.is_empty() {
-                        expanded_path.clone()
-                    } else {
-                        format!("{}:{}", expanded_path, current_path)
-                    };
-                    unsafe {
-                        env::set_var("PATH", new_full_path);
-                    }
-                    println!("{}: Added {} to PATH", "path".green().bold(), expanded_path);
-                }
-            } else {
-                eprintln!("{}: Usage: path [directory]", "path".red().bold());
-            }
-            Ok(Some(true))
+        saved.push((assignment.name.clone(), previous));
+        if let Some(variable) = state.variables.get_mut(&assignment.name) {
+            variable.exported = true;
         }
-        "edit" => {
-            let editor = env::var("EDITOR").unwrap_or_else(|_| "vim".to_string());
-            let last_command = if args.is_empty() {
-                rl.history()
-                    .into_iter()
-                    .rev()
-                    .nth(1)
-                    .map(|entry| entry.to_string())
-            } else {
-                Some(args.join(" "))
-            };
+    }
+    Ok(saved)
+}
 
-            if let Some(cmd) = last_command {
-                let temp_file_path = Path::new("/tmp/last_command");
-                std::fs::write(temp_file_path, cmd)?;
-                let status = Command::new(editor).arg(temp_file_path).status()?;
-                if status.success() {
-                    let edited_command = std::fs::read_to_string(temp_file_path)?;
-                    let edited_parts = parse_arguments(edited_command.trim());
-                    if !edited_parts.is_empty() {
-                        let edited_cmd = &edited_parts[0];
-                        let edited_args: Vec<&str> =
-                            edited_parts[1..].iter().map(|s| s.as_str()).collect();
-                        execute_single_command(
-                            edited_cmd,
-                            &edited_args,
-                            aliases,
-                            true,
-                            edited_command.trim(),
-                        );
-                    }
-                } else {
-                    eprintln!(
-                        "{}: Editor exited with status: {}",
-                        "Warning".yellow().bold(),
-                        status
-                    );
-                }
-            } else {
-                eprintln!("{}: No previous command to edit.", "Info".blue().bold());
-            }
-            Ok(Some(true))
+// A line of nothing but assignments sets shell variables
+fn assign_variables(assignments: &[Assignment], state: &mut ShellState) -> i32 {
+    for assignment in assignments {
+        let assigned = expand_word(&assignment.value, state)
+            .map_err(Box::<dyn std::error::Error>::from)
+            .and_then(|value| Ok(state.set_var(&assignment.name, &value)?));
+        if let Err(e) = assigned {
+            eprintln!("{}: {e}", "Error".red().bold());
+            return 1;
         }
-        _ => Ok(None),
     }
+    0
 }
 
-pub fn execute_file_commands(
-    file: &Option<PathBuf>,
-    aliases: &mut HashMap<String, String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    if let Some(file_path) = file {
-        if file_path.exists() {
-            let content = std::fs::read_to_string(file_path)?;
-            for line in content.lines() {
-                let input = line.trim();
-                if input.is_empty() {
-                    continue;
-                }
+fn execute_simple(
+    command: &str,
+    args: &[String],
+    state: &mut ShellState,
+) -> Result<Flow, Box<dyn std::error::Error>> {
+    if let Some(function) = state.functions.get(command).cloned() {
+        return call_function(&function, args, state);
+    }
 
-                let parts = parse_arguments(input);
-                if parts.is_empty() {
-                    continue;
-                }
+    let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+    match builtins::find(command) {
+        Some(builtin) => builtin.run(&args, state),
+        None => Ok(Flow::Status(execute_command(command, &args, state))),
+    }
+}
 
-                let command = &parts[0];
-                let args: Vec<&str> = parts[1..].iter().map(|s| s.as_str()).collect();
-
-                match command.as_str() {
-                    "exit" => break,
-                    "alias" => {
-                        if args.is_empty() {
-                            for (name, value) in aliases.iter() {
-                                println!("alias {}=\"{}\"", name, value);
-                            }
-                        } else if args.len() == 1 && args[0].contains('=') {
-                            let alias_def = args[0];
-                            if let Some(eq_pos) = alias_def.find('=') {
-                                let name = alias_def[..eq_pos].to_string();
-                                let value = alias_def[eq_pos + 1..].trim_matches('"').to_string();
-                                aliases.insert(name, value);
-                            }
-                        } else {
-                            eprintln!("{}: Usage: alias [name=value]", "alias".red().bold());
-                        }
-                    }
-                    "path" => {
-                        if args.is_empty() {
-                            if let Ok(path) = env::var("PATH") {
-                                println!("{}", path);
-                            } else {
-                                println!();
-                            }
-                        } else if args.len() == 1 {
-                            let new_path = args[0];
-                            let expanded_path = if new_path.starts_with("~") {
-                                let home_dir =
-                                    dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
-                                home_dir.join(&new_path[2..]).to_string_lossy().to_string()
-                            } else {
-                                new_path.to_string()
-                            };
-
-                            let path_buf = PathBuf::from(&expanded_path);
-                            if !path_buf.exists() {
-                                eprintln!(
-                                    "{}: Directory does not exist: {}",
-                                    "path".red().bold(),
-                                    expanded_path
-                                );
-                            } else if !path_buf.is_dir() {
-                                eprintln!(
-                                    "{}: Not a directory: {}",
-                                    "path".red().bold(),
-                                    expanded_path
-                                );
-                            } else {
-                                let current_path = env::var("PATH").unwrap_or_default();
-                                let new_full_path = if current_path.is_empty() {
-                                    expanded_path.clone()
-                                } else {
-                                    format!("{}:{}", expanded_path, current_path)
-                                };
-                                unsafe {
-                                    env::set_var("PATH", new_full_path);
-                                }
-                                println!(
-                                    "{}: Added {} to PATH",
-                                    "path".green().bold(),
-                                    expanded_path
-                                );
-                            }
-                        } else {
-                            eprintln!("{}: Usage: path [directory]", "path".red().bold());
-                        }
-                    }
-                    _ => {
-                        execute_single_command(command, &args, aliases, false, input);
-                    }
-                }
+pub fn execute_pipeline(
+    pipeline: &Pipeline,
+    state: &mut ShellState,
+) -> Result<Flow, Box<dyn std::error::Error>> {
+    let Some(stage) = pipeline.simple() else {
+        if pipeline.commands.len() > 1 {
+            return Ok(Flow::Status(execute_piped_commands(pipeline, state)));
+        }
+        return run_pipeline(pipeline, state);
+    };
+
+    let parts = match expand_words(&stage.words, state) {
+        Ok(parts) => parts,
+        Err(e) => {
+            eprintln!("{}: {e}", "Error".red().bold());
+            return Ok(Flow::Status(1));
+        }
+    };
+
+    let mut guard = RedirectGuard::new();
+    if let Err(e) = guard.apply(&stage.redirects, state) {
+        eprintln!("{}: {e}", "Error".red().bold());
+        return Ok(Flow::Status(1));
+    }
+
+    let Some((command, args)) = parts.split_first() else {
+        return Ok(Flow::Status(assign_variables(&stage.assignments, state)));
+    };
+    let saved = match apply_assignments(&stage.assignments, state) {
+        Ok(saved) => saved,
+        Err(e) => {
+            eprintln!("{}: {e}", "Error".red().bold());
+            return Ok(Flow::Status(1));
+        }
+    };
+
+    let result = execute_simple(command, args, state);
+    state.restore_variables(saved);
+    result
+}
+
+pub fn execute_input(
+    input: &str,
+    state: &mut ShellState,
+) -> Result<Flow, Box<dyn std::error::Error>> {
+    let mut parser = Parser::new(input)?;
+    let mut flow = Flow::Status(0);
+    while let Some(list) = parser.next_command(&state.aliases)? {
+        for and_or in &list.items {
+            flow = execute_and_or(and_or, state)?;
+            if flow == Flow::Exit {
+                return Ok(flow);
+            }
+            // At the prompt only the current line is abandoned; a script
+            // exits
+            if state.jump == Some(Jump::Abort) {
+                state.jump = None;
+                return Ok(if state.interactive {
+                    Flow::Status(state.last_status)
+                } else {
+                    Flow::Exit
+                });
             }
-        } else {
-            eprintln!(
-                "{}: File not found: {}",
-                "Error".red().bold(),
-                file_path.display()
-            );
         }
     }
-    Ok(())
+    Ok(flow)
 }
@@ -1,12 +1,14 @@
+use crate::builtins;
 use colored::*;
 use rustyline::completion::{Completer, Pair};
 use rustyline::config::Config;
 use rustyline::highlight::{CmdKind, Highlighter, MatchingBracketHighlighter};
 use rustyline::hint::{Hinter, HistoryHinter};
-use rustyline::validate::{MatchingBracketValidator, Validator};
+use rustyline::validate::Validator;
 use rustyline::{CompletionType, Helper};
 use rustyline::{Context, Editor};
 use std::borrow::Cow;
+use std::cell::RefCell;
 use std::env;
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
@@ -14,8 +16,17 @@ use std::path::Path;
 pub struct ShellHelper {
     completer: ShellCompleter,
     hinter: HistoryHinter,
-    validator: MatchingBracketValidator,
     highlighter: MatchingBracketHighlighter,
+    edited: RefCell<Edited>,
+}
+
+// The line as the line editor last drew it, so that it can be drawn again
+// after the prompt changes
+#[derive(Clone, Default)]
+pub struct Edited {
+    pub line: String,
+    pub pos: usize,
+    pub hint: Option<String>,
 }
 
 impl Default for ShellHelper {
@@ -29,10 +40,29 @@ impl ShellHelper {
         ShellHelper {
             completer: ShellCompleter::new(),
             hinter: HistoryHinter::new(),
-            validator: MatchingBracketValidator::new(),
             highlighter: MatchingBracketHighlighter::new(),
+            edited: RefCell::default(),
         }
     }
+
+    pub fn set_functions(&mut self, names: Vec<String>) {
+        self.completer.functions = names;
+    }
+
+    pub fn edited(&self) -> Edited {
+        self.edited.borrow().clone()
+    }
+
+    fn set_edited(&self, line: &str, pos: usize) {
+        let mut edited = self.edited.borrow_mut();
+        edited.line = line.to_string();
+        edited.pos = pos;
+    }
+
+    // The shell's PATH, which the process environment does not follow
+    pub fn set_path(&mut self, path: Option<String>) {
+        self.completer.path = path;
+    }
 }
 
 impl Helper for ShellHelper {}
@@ -54,22 +84,15 @@ impl Hinter for ShellHelper {
     type Hint = String;
 
     fn hint(&self, line: &str, pos: usize, ctx: &Context<'_>) -> Option<String> {
-        self.hinter.hint(line, pos, ctx)
+        let hint = self.hinter.hint(line, pos, ctx);
+        self.set_edited(line, pos);
+        self.edited.borrow_mut().hint = hint.clone();
+        hint
     }
 }
 
-impl Validator for ShellHelper {
-    fn validate(
-        &self,
-        ctx: &mut rustyline::validate::ValidationContext,
-    ) -> rustyline::Result<rustyline::validate::ValidationResult> {
-        self.validator.validate(ctx)
-    }
-
-    fn validate_while_typing(&self) -> bool {
-        self.validator.validate_while_typing()
-    }
-}
+// Unfinished commands are read on to the end at the PS2 prompt instead
+impl Validator for ShellHelper {}
 
 impl Highlighter for ShellHelper {
     fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
@@ -85,44 +108,42 @@ impl Highlighter for ShellHelper {
     }
 
     fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        self.set_edited(line, pos);
         self.highlighter.highlight(line, pos)
     }
 
     fn highlight_char(&self, line: &str, pos: usize, kind: CmdKind) -> bool {
+        self.set_edited(line, pos);
         self.highlighter.highlight_char(line, pos, kind)
     }
 }
 
-struct ShellCompleter;
+struct ShellCompleter {
+    functions: Vec<String>,
+    path: Option<String>,
+}
 
 impl ShellCompleter {
     fn new() -> ShellCompleter {
-        ShellCompleter
-    }
-
-    fn get_builtin_commands() -> Vec<String> {
-        vec![
-            "cd".to_string(),
-            "edit".to_string(),
-            "exit".to_string(),
-            "alias".to_string(),
-            "set".to_string(),
-        ]
+        ShellCompleter {
+            functions: Vec::new(),
+            path: env::var("PATH").ok(),
+        }
     }
 
-    fn get_path_commands() -> Vec<String> {
+    fn get_path_commands(&self) -> Vec<String> {
         let mut commands = Vec::new();
 
-        if let Ok(path_var) = env::var("PATH") {
+        if let Some(path_var) = &self.path {
             for path in path_var.split(':') {
                 if let Ok(entries) = std::fs::read_dir(path) {
                     for entry in entries.flatten() {
-                        if let Ok(metadata) = entry.metadata() {
-                            if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
-                                if let Some(name) = entry.file_name().to_str() {
-                                    commands.push(name.to_string());
-                                }
-                            }
+                        if let Ok(metadata) = entry.metadata()
+                            && metadata.is_file()
+                            && metadata.permissions().mode() & 0o111 != 0
+                            && let Some(name) = entry.file_name().to_str()
+                        {
+                            commands.push(name.to_string());
                         }
                     }
                 }
@@ -170,53 +191,53 @@ impl ShellCompleter {
 
         if let Ok(entries) = std::fs::read_dir(dir_path) {
             for entry in entries.flatten() {
-                if let Some(name) = entry.file_name().to_str() {
-                    if name.starts_with(filename_prefix) {
-                        let is_dir = entry.file_type().is_ok_and(|ft| ft.is_dir());
-                        let display_name = if is_dir {
-                            format!("{}/", name)
+                if let Some(name) = entry.file_name().to_str()
+                    && name.starts_with(filename_prefix)
+                {
+                    let is_dir = entry.file_type().is_ok_and(|ft| ft.is_dir());
+                    let display_name = if is_dir {
+                        format!("{}/", name)
+                    } else {
+                        name.to_string()
+                    };
+
+                    let base_replacement = if partial_path.starts_with("~/") {
+                        if dir_path == dirs::home_dir().unwrap_or_default() {
+                            format!("~/{}", name)
                         } else {
-                            name.to_string()
-                        };
-
-                        let base_replacement = if partial_path.starts_with("~/") {
-                            if dir_path == dirs::home_dir().unwrap_or_default() {
+                            let relative_dir = dir_path
+                                .strip_prefix(dirs::home_dir().unwrap_or_default())
+                                .unwrap_or(dir_path);
+                            if relative_dir == Path::new("") {
                                 format!("~/{}", name)
                             } else {
-                                let relative_dir = dir_path
-                                    .strip_prefix(dirs::home_dir().unwrap_or_default())
-                                    .unwrap_or(dir_path);
-                                if relative_dir == Path::new("") {
-                                    format!("~/{}", name)
-                                } else {
-                                    format!("~/{}/{}", relative_dir.display(), name)
-                                }
-                            }
-                        } else if dir_path == Path::new(".") {
-                            name.to_string()
-                        } else if expanded_path.ends_with('/') {
-                            format!("{}{}", expanded_path, name)
-                        } else if expanded_path.contains('/') {
-                            if dir_path == Path::new("/") {
-                                format!("/{}", name)
-                            } else {
-                                format!("{}/{}", dir_path.display(), name)
+                                format!("~/{}/{}", relative_dir.display(), name)
                             }
+                        }
+                    } else if dir_path == Path::new(".") {
+                        name.to_string()
+                    } else if expanded_path.ends_with('/') {
+                        format!("{}{}", expanded_path, name)
+                    } else if expanded_path.contains('/') {
+                        if dir_path == Path::new("/") {
+                            format!("/{}", name)
                         } else {
-                            name.to_string()
-                        };
+                            format!("{}/{}", dir_path.display(), name)
+                        }
+                    } else {
+                        name.to_string()
+                    };
 
-                        let replacement = if is_dir {
-                            format!("{}/", base_replacement)
-                        } else {
-                            base_replacement
-                        };
+                    let replacement = if is_dir {
+                        format!("{}/", base_replacement)
+                    } else {
+                        base_replacement
+                    };
 
-                        candidates.push(Pair {
-                            display: display_name,
-                            replacement,
-                        });
-                    }
+                    candidates.push(Pair {
+                        display: display_name,
+                        replacement,
+                    });
                 }
             }
         }
@@ -242,16 +263,25 @@ impl Completer for ShellCompleter {
 
             let mut candidates = Vec::new();
 
-            for cmd in Self::get_builtin_commands() {
+            for cmd in builtins::names() {
                 if cmd.starts_with(word_to_complete) {
                     candidates.push(Pair {
                         display: format!("{} {}", cmd, "(builtin)".bright_black()),
-                        replacement: cmd,
+                        replacement: cmd.to_string(),
+                    });
+                }
+            }
+
+            for name in &self.functions {
+                if name.starts_with(word_to_complete) {
+                    candidates.push(Pair {
+                        display: format!("{} {}", name, "(function)".bright_black()),
+                        replacement: name.clone(),
                     });
                 }
             }
 
-            for cmd in Self::get_path_commands() {
+            for cmd in self.get_path_commands() {
                 if cmd.starts_with(word_to_complete) {
                     candidates.push(Pair {
                         display: cmd.clone(),
@@ -274,8 +304,11 @@ impl Completer for ShellCompleter {
 
 pub fn create_editor()
 -> Result<Editor<ShellHelper, rustyline::history::FileHistory>, Box<dyn std::error::Error>> {
+    // Every entry is kept, as the shell's own history keeps them, so that
+    // the two stay in step
     let config = Config::builder()
         .completion_type(CompletionType::List)
+        .history_ignore_dups(false)?
         .build();
     let helper = ShellHelper::new();
     let mut rl = Editor::with_config(config)?;
@@ -0,0 +1,354 @@
+use crate::arith::evaluate;
+use crate::ast::CondExpr;
+use crate::expand::{expand_pattern, expand_regex, expand_word};
+use crate::glob::pattern_matches;
+use crate::state::ShellState;
+use regex::Regex;
+use std::ffi::CString;
+use std::fs::{self, Metadata};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+const UNARY_OPERATORS: [&str; 23] = [
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-h", "-k", "-L", "-n", "-O", "-G", "-p", "-r", "-s",
+    "-S", "-t", "-u", "-v", "-w", "-x", "-z",
+];
+
+const BINARY_OPERATORS: [&str; 15] = [
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef", "=~",
+];
+
+pub fn is_unary_operator(op: &str) -> bool {
+    UNARY_OPERATORS.contains(&op)
+}
+
+pub fn is_binary_operator(op: &str) -> bool {
+    BINARY_OPERATORS.contains(&op)
+}
+
+fn resolve(state: &ShellState, path: &str) -> Option<PathBuf> {
+    // An empty name is no file at all, not the working directory
+    (!path.is_empty()).then(|| state.cwd.join(path))
+}
+
+fn accessible(path: &Path, mode: libc::c_int) -> bool {
+    CString::new(path.as_os_str().as_bytes())
+        .is_ok_and(|path| unsafe { libc::access(path.as_ptr(), mode) } == 0)
+}
+
+fn unary_test(op: &str, operand: &str, state: &ShellState) -> Result<bool, String> {
+    let path = resolve(state, operand);
+    let metadata = || path.as_ref().and_then(|path| fs::metadata(path).ok());
+    let has = |check: fn(&Metadata) -> bool| metadata().is_some_and(|m| check(&m));
+
+    Ok(match op {
+        "-z" => operand.is_empty(),
+        "-n" => !operand.is_empty(),
+        "-v" => state.variables.contains_key(operand),
+        "-a" | "-e" => metadata().is_some(),
+        "-f" => has(|m| m.is_file()),
+        "-d" => has(|m| m.is_dir()),
+        "-b" => has(|m| m.file_type().is_block_device()),
+        "-c" => has(|m| m.file_type().is_char_device()),
+        "-p" => has(|m| m.file_type().is_fifo()),
+        "-S" => has(|m| m.file_type().is_socket()),
+        "-s" => has(|m| m.len() > 0),
+        "-g" => has(|m| m.mode() & libc::S_ISGID != 0),
+        "-u" => has(|m| m.mode() & libc::S_ISUID != 0),
+        "-k" => has(|m| m.mode() & libc::S_ISVTX != 0),
+        "-O" => has(|m| m.uid() == unsafe { libc::geteuid() }),
+        "-G" => has(|m| m.gid() == unsafe { libc::getegid() }),
+        "-h" | "-L" => path
+            .and_then(|path| fs::symlink_metadata(path).ok())
+            .is_some_and(|m| m.file_type().is_symlink()),
+        "-r" => path.is_some_and(|path| accessible(&path, libc::R_OK)),
+        "-w" => path.is_some_and(|path| accessible(&path, libc::W_OK)),
+        "-x" => path.is_some_and(|path| accessible(&path, libc::X_OK)),
+        "-t" => {
+            let fd: i32 = parse_integer(operand)?.try_into().unwrap_or(-1);
+            unsafe { libc::isatty(fd) == 1 }
+        }
+        _ => return Err(format!("{op}: unary operator expected")),
+    })
+}
+
+fn parse_integer(text: &str) -> Result<i64, String> {
+    text.trim()
+        .parse()
+        .map_err(|_| format!("{text}: integer expression expected"))
+}
+
+fn compare_integers(left: i64, op: &str, right: i64) -> bool {
+    match op {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        _ => left >= right,
+    }
+}
+
+fn compare_files(left: &str, op: &str, right: &str, state: &ShellState) -> bool {
+    let metadata = |path: &str| resolve(state, path).and_then(|path| fs::metadata(path).ok());
+    let (left, right) = (metadata(left), metadata(right));
+    let modified = |m: &Option<Metadata>| m.as_ref().and_then(|m| m.modified().ok());
+
+    match op {
+        "-nt" => match (modified(&left), modified(&right)) {
+            (Some(left), Some(right)) => left > right,
+            (left, _) => left.is_some(),
+        },
+        "-ot" => match (modified(&left), modified(&right)) {
+            (Some(left), Some(right)) => left < right,
+            (_, right) => right.is_some(),
+        },
+        _ => match (left, right) {
+            (Some(left), Some(right)) => left.dev() == right.dev() && left.ino() == right.ino(),
+            _ => false,
+        },
+    }
+}
+
+// Operators that `test` and `[[ ]]` treat alike
+fn binary_test(left: &str, op: &str, right: &str, state: &ShellState) -> Result<bool, String> {
+    Ok(match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        ">" => left > right,
+        "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+            compare_integers(parse_integer(left)?, op, parse_integer(right)?)
+        }
+        "-nt" | "-ot" | "-ef" => compare_files(left, op, right, state),
+        _ => return Err(format!("{op}: binary operator expected")),
+    })
+}
+
+// The arguments of `test` and `[`, parsed with `-o` binding more loosely
+// than `-a`, which binds more loosely than `!`
+struct TestArgs<'a> {
+    args: &'a [&'a str],
+    pos: usize,
+}
+
+impl TestArgs<'_> {
+    fn next(&mut self) -> Result<&str, String> {
+        let arg = self.args.get(self.pos).ok_or("argument expected")?;
+        self.pos += 1;
+        Ok(arg)
+    }
+
+    fn or(&mut self, state: &ShellState) -> Result<bool, String> {
+        let mut value = self.and(state)?;
+        while self.args.get(self.pos) == Some(&"-o") {
+            self.pos += 1;
+            value |= self.and(state)?;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self, state: &ShellState) -> Result<bool, String> {
+        let mut value = self.not(state)?;
+        while self.args.get(self.pos) == Some(&"-a") {
+            self.pos += 1;
+            value &= self.not(state)?;
+        }
+        Ok(value)
+    }
+
+    fn not(&mut self, state: &ShellState) -> Result<bool, String> {
+        if self.args.get(self.pos) == Some(&"!") {
+            self.pos += 1;
+            return Ok(!self.not(state)?);
+        }
+        self.primary(state)
+    }
+
+    fn primary(&mut self, state: &ShellState) -> Result<bool, String> {
+        let rest = &self.args[self.pos..];
+        if rest.len() >= 3 && is_binary_operator(rest[1]) && rest[1] != "=~" {
+            self.pos += 3;
+            return binary_test(rest[0], rest[1], rest[2], state);
+        }
+        if rest.first() == Some(&"(") {
+            self.pos += 1;
+            let value = self.or(state)?;
+            if self.next()? != ")" {
+                return Err("`)' expected".to_string());
+            }
+            return Ok(value);
+        }
+        if rest.len() >= 2 && is_unary_operator(rest[0]) {
+            self.pos += 2;
+            return unary_test(rest[0], rest[1], state);
+        }
+        Ok(!self.next()?.is_empty())
+    }
+}
+
+// `test` and `[`. Up to four arguments are read by their count, as POSIX
+// lays out, so that an operand such as "!" or "-n" is still an operand.
+pub fn test(args: &[&str], state: &ShellState) -> Result<bool, String> {
+    match args {
+        [] => Ok(false),
+        [word] => Ok(!word.is_empty()),
+        ["!", word] => Ok(word.is_empty()),
+        [op, operand] if is_unary_operator(op) => unary_test(op, operand, state),
+        [op, _] => Err(format!("{op}: unary operator expected")),
+        [left, op, right] if is_binary_operator(op) && *op != "=~" => {
+            binary_test(left, op, right, state)
+        }
+        ["!", rest @ ..] if args.len() <= 4 => Ok(!test(rest, state)?),
+        ["(", rest @ .., ")"] if args.len() <= 4 => test(rest, state),
+        _ => {
+            let mut parser = TestArgs { args, pos: 0 };
+            let value = parser.or(state)?;
+            match args.get(parser.pos) {
+                Some(extra) => Err(format!("{extra}: too many arguments")),
+                None => Ok(value),
+            }
+        }
+    }
+}
+
+// `[[ ]]`, whose operands are expanded without splitting or globbing. The
+// right side of `==` and `!=` is a pattern, `=~` takes an extended regex and
+// the integer comparisons take arithmetic expressions.
+pub fn evaluate_conditional(
+    expression: &CondExpr,
+    state: &mut ShellState,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(match expression {
+        CondExpr::Word(word) => !expand_word(word, state)?.is_empty(),
+        CondExpr::Unary(op, word) => {
+            let operand = expand_word(word, state)?;
+            unary_test(op, &operand, state)?
+        }
+        CondExpr::Binary(left, op, right) => {
+            let left = expand_word(left, state)?;
+            match op.as_str() {
+                "=" | "==" => pattern_matches(&expand_pattern(right, state)?, &left),
+                "!=" => !pattern_matches(&expand_pattern(right, state)?, &left),
+                "=~" => Regex::new(&expand_regex(right, state)?)?.is_match(&left),
+                "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+                    let left = evaluate(&left, state)?;
+                    let right = evaluate(&expand_word(right, state)?, state)?;
+                    compare_integers(left, op, right)
+                }
+                _ => {
+                    let right = expand_word(right, state)?;
+                    binary_test(&left, op, &right, state)?
+                }
+            }
+        }
+        CondExpr::Not(expression) => !evaluate_conditional(expression, state)?,
+        CondExpr::And(left, right) => {
+            evaluate_conditional(left, state)? && evaluate_conditional(right, state)?
+        }
+        CondExpr::Or(left, right) => {
+            evaluate_conditional(left, state)? || evaluate_conditional(right, state)?
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::Shell;
+
+    fn check(args: &[&str]) -> bool {
+        test(args, &ShellState::new()).unwrap()
+    }
+
+    fn conditional(line: &str) -> bool {
+        let mut shell = Shell::new();
+        shell.run_line(line).unwrap();
+        shell.last_status() == 0
+    }
+
+    #[test]
+    fn file_operators() {
+        let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let source = concat!(env!("CARGO_MANIFEST_DIR"), "/src");
+        assert!(check(&["-e", manifest]));
+        assert!(check(&["-f", manifest]));
+        assert!(!check(&["-d", manifest]));
+        assert!(check(&["-d", source]));
+        assert!(check(&["-s", manifest]));
+        assert!(check(&["-r", manifest]));
+        assert!(!check(&["-e", "/no/such/file"]));
+        assert!(!check(&["-e", ""]));
+        assert!(check(&[manifest, "-ef", manifest]));
+    }
+
+    #[test]
+    fn string_comparisons() {
+        assert!(check(&["abc"]));
+        assert!(!check(&[""]));
+        assert!(check(&["-n", "abc"]));
+        assert!(check(&["-z", ""]));
+        assert!(check(&["abc", "=", "abc"]));
+        assert!(check(&["abc", "!=", "abd"]));
+        assert!(check(&["abc", "<", "abd"]));
+        assert!(!check(&["abc", ">", "abd"]));
+        // Operators stand for themselves where an operand is expected
+        assert!(check(&["-n"]));
+        assert!(check(&["!", ""]));
+        assert!(check(&["=", "=", "="]));
+    }
+
+    #[test]
+    fn integer_comparisons() {
+        assert!(check(&["2", "-eq", "2"]));
+        assert!(check(&["2", "-ne", "3"]));
+        assert!(check(&["-1", "-lt", "0"]));
+        assert!(check(&["3", "-le", "3"]));
+        assert!(check(&["10", "-gt", "9"]));
+        assert!(!check(&["9", "-ge", "10"]));
+        assert!(test(&["a", "-eq", "1"], &ShellState::new()).is_err());
+    }
+
+    #[test]
+    fn negation_and_connectives() {
+        assert!(check(&["!", "a", "=", "b"]));
+        assert!(check(&["a", "-a", "b"]));
+        assert!(!check(&["a", "-a", ""]));
+        assert!(check(&["", "-o", "b"]));
+        assert!(!check(&["", "-o", ""]));
+        // -a binds more tightly than -o
+        assert!(check(&["a", "-o", "", "-a", ""]));
+        assert!(check(&["!", "", "-a", "(", "1", "-eq", "1", ")"]));
+        assert!(test(&["a", "b", "c"], &ShellState::new()).is_err());
+    }
+
+    #[test]
+    fn bracket_is_test_with_a_closing_bracket() {
+        assert!(conditional("[ 1 -lt 2 ]"));
+        assert!(!conditional("[ -z abc ]"));
+        assert!(conditional("[ ! -d /no/such/dir -a x = x ]"));
+    }
+
+    #[test]
+    fn double_brackets_match_patterns() {
+        assert!(conditional("[[ file.rs == *.rs ]]"));
+        assert!(!conditional("[[ file.rs == *.go ]]"));
+        assert!(conditional("[[ file.rs != *.go ]]"));
+        // A quoted pattern is matched literally
+        assert!(!conditional("[[ file.rs == '*.rs' ]]"));
+        assert!(conditional("x='a b'; [[ $x == 'a b' ]]"));
+        assert!(conditional("[[ -n x && ! -z x || -z '' ]]"));
+        assert!(conditional("[[ 1+1 -eq 2 ]]"));
+    }
+
+    #[test]
+    fn double_brackets_match_regexes() {
+        assert!(conditional("[[ abc123 =~ ^[a-z]+[0-9]+$ ]]"));
+        assert!(!conditional("[[ abc =~ ^[0-9]+$ ]]"));
+        assert!(conditional("re='b+'; [[ abbbc =~ $re ]]"));
+        // Quoted parts of the regex are literal
+        assert!(!conditional("[[ abc =~ 'a.c' ]]"));
+        assert!(conditional("[[ a.c =~ 'a.c' ]]"));
+    }
+}
@@ -0,0 +1,371 @@
+use crate::ast::{CaseArm, Command, CompoundCommand, Function, List, Pipeline, Redirect, Word};
+use crate::commands::{Flow, execute_and_or, execute_pipeline};
+use crate::condition::evaluate_conditional;
+use crate::expand::{evaluate_arithmetic, expand_pattern, expand_word, expand_words};
+use crate::glob::pattern_matches;
+use crate::redirect::RedirectGuard;
+use crate::state::{Jump, ShellState};
+use colored::*;
+use std::rc::Rc;
+
+// An interrupted command stops every list and loop around it
+const INTERRUPTED: Flow = Flow::Status(128 + libc::SIGINT);
+
+// Compound commands and function definitions are run here; everything
+// else goes through `execute_pipeline`.
+pub fn run_pipeline(
+    pipeline: &Pipeline,
+    state: &mut ShellState,
+) -> Result<Flow, Box<dyn std::error::Error>> {
+    match pipeline.commands.as_slice() {
+        [Command::Compound(command, redirects)] => execute_compound(command, redirects, state),
+        [Command::Function(function)] => {
+            state
+                .functions
+                .insert(function.name.clone(), Rc::clone(function));
+            Ok(Flow::Status(0))
+        }
+        _ => execute_pipeline(pipeline, state),
+    }
+}
+
+fn execute_list(list: &List, state: &mut ShellState) -> Result<Flow, Box<dyn std::error::Error>> {
+    let mut flow = Flow::Status(0);
+    for and_or in &list.items {
+        flow = execute_and_or(and_or, state)?;
+        if flow == Flow::Exit || flow == INTERRUPTED || state.jump.is_some() {
+            break;
+        }
+    }
+    Ok(flow)
+}
+
+fn execute_condition(
+    list: &List,
+    state: &mut ShellState,
+) -> Result<Flow, Box<dyn std::error::Error>> {
+    let outer = state.in_condition;
+    state.in_condition = true;
+    let flow = execute_list(list, state);
+    state.in_condition = outer;
+    flow
+}
+
+pub fn execute_compound(
+    command: &CompoundCommand,
+    redirects: &[Redirect],
+    state: &mut ShellState,
+) -> Result<Flow, Box<dyn std::error::Error>> {
+    let mut guard = RedirectGuard::new();
+    if let Err(e) = guard.apply(redirects, state) {
+        eprintln!("{}: {e}", "Error".red().bold());
+        return Ok(Flow::Status(1));
+    }
+
+    match command {
+        CompoundCommand::Group(body) => execute_list(body, state),
+        CompoundCommand::If {
+            branches,
+            else_body,
+        } => {
+            for (condition, body) in branches {
+                match execute_condition(condition, state)? {
+                    flow if state.jump.is_some() => return Ok(flow),
+                    Flow::Status(0) => return execute_list(body, state),
+                    Flow::Status(_) => {}
+                    Flow::Exit => return Ok(Flow::Exit),
+                }
+            }
+            match else_body {
+                Some(body) => execute_list(body, state),
+                None => Ok(Flow::Status(0)),
+            }
+        }
+        CompoundCommand::While {
+            condition,
+            body,
+            until,
+        } => {
+            state.loop_depth += 1;
+            let flow = execute_while(condition, body, *until, state);
+            state.loop_depth -= 1;
+            flow
+        }
+        CompoundCommand::For {
+            variable,
+            words,
+            body,
+        } => {
+            let values = match words {
+                Some(words) => match expand_words(words, state) {
+                    Ok(values) => values,
+                    Err(e) => {
+                        eprintln!("{}: {e}", "Error".red().bold());
+                        return Ok(Flow::Status(1));
+                    }
+                },
+                None => state.positional.clone(),
+            };
+
+            state.loop_depth += 1;
+            let flow = execute_for(variable, &values, body, state);
+            state.loop_depth -= 1;
+            flow
+        }
+        CompoundCommand::Case { word, arms } => execute_case(word, arms, state),
+        // True when the value is not zero
+        CompoundCommand::Arithmetic(expression) => match evaluate_arithmetic(expression, state) {
+            Ok(value) => Ok(Flow::Status((value == 0) as i32)),
+            Err(e) => {
+                eprintln!("{}: {e}", "Error".red().bold());
+                Ok(Flow::Status(1))
+            }
+        },
+        CompoundCommand::Conditional(expression) => match evaluate_conditional(expression, state) {
+            Ok(value) => Ok(Flow::Status(!value as i32)),
+            Err(e) => {
+                eprintln!("{}: {e}", "Error".red().bold());
+                Ok(Flow::Status(2))
+            }
+        },
+    }
+}
+
+// Consumes a break or continue aimed at the innermost loop. Returns true
+// when that loop has to stop.
+fn leave_iteration(state: &mut ShellState) -> bool {
+    match state.jump {
+        None => false,
+        Some(Jump::Break(count)) => {
+            state.jump = (count > 1).then(|| Jump::Break(count - 1));
+            true
+        }
+        Some(Jump::Continue(count)) => {
+            state.jump = (count > 1).then(|| Jump::Continue(count - 1));
+            count > 1
+        }
+        Some(Jump::Return | Jump::Abort) => true,
+    }
+}
+
+// Deep enough for real recursion while staying well inside the stack
+const MAX_FUNCTION_DEPTH: usize = 1000;
+
+pub fn call_function(
+    function: &Function,
+    args: &[String],
+    state: &mut ShellState,
+) -> Result<Flow, Box<dyn std::error::Error>> {
+    if state.local_scopes.len() >= MAX_FUNCTION_DEPTH {
+        eprintln!(
+            "{}: maximum function nesting level exceeded ({MAX_FUNCTION_DEPTH})",
+            function.name.red().bold()
+        );
+        return Ok(Flow::Status(1));
+    }
+
+    let positional = std::mem::replace(&mut state.positional, args.to_vec());
+    let loop_depth = std::mem::take(&mut state.loop_depth);
+    state.local_scopes.push(Vec::new());
+
+    let result = execute_compound(&function.body, &function.redirects, state);
+
+    let saved = state.local_scopes.pop().unwrap_or_default();
+    state.restore_variables(saved);
+    state.loop_depth = loop_depth;
+    state.positional = positional;
+
+    if state.jump == Some(Jump::Return) {
+        state.jump = None;
+        return result.map(|flow| match flow {
+            Flow::Status(_) => Flow::Status(state.last_status),
+            Flow::Exit => Flow::Exit,
+        });
+    }
+    result
+}
+
+fn execute_while(
+    condition: &List,
+    body: &List,
+    until: bool,
+    state: &mut ShellState,
+) -> Result<Flow, Box<dyn std::error::Error>> {
+    let mut status = 0;
+
+    loop {
+        let flow = execute_condition(condition, state)?;
+        let Flow::Status(code) = flow else {
+            return Ok(Flow::Exit);
+        };
+        if flow == INTERRUPTED {
+            return Ok(flow);
+        }
+        if state.jump.is_some() {
+            if leave_iteration(state) {
+                break;
+            }
+            continue;
+        }
+        if (code == 0) == until {
+            break;
+        }
+
+        let flow = execute_list(body, state)?;
+        let Flow::Status(code) = flow else {
+            return Ok(Flow::Exit);
+        };
+        status = code;
+        if flow == INTERRUPTED || leave_iteration(state) {
+            break;
+        }
+    }
+
+    Ok(Flow::Status(status))
+}
+
+fn execute_for(
+    variable: &str,
+    values: &[String],
+    body: &List,
+    state: &mut ShellState,
+) -> Result<Flow, Box<dyn std::error::Error>> {
+    let mut status = 0;
+
+    for value in values {
+        if let Err(e) = state.set_var(variable, value) {
+            eprintln!("{}: {e}", "Error".red().bold());
+            return Ok(Flow::Status(1));
+        }
+
+        let flow = execute_list(body, state)?;
+        let Flow::Status(code) = flow else {
+            return Ok(Flow::Exit);
+        };
+        status = code;
+        if flow == INTERRUPTED || leave_iteration(state) {
+            break;
+        }
+    }
+
+    Ok(Flow::Status(status))
+}
+
+fn execute_case(
+    word: &Word,
+    arms: &[CaseArm],
+    state: &mut ShellState,
+) -> Result<Flow, Box<dyn std::error::Error>> {
+    let matched = expand_word(word, state).and_then(|subject| {
+        for arm in arms {
+            for pattern in &arm.patterns {
+                if pattern_matches(&expand_pattern(pattern, state)?, &subject) {
+                    return Ok(Some(&arm.body));
+                }
+            }
+        }
+        Ok(None)
+    });
+
+    match matched {
+        Ok(Some(body)) => execute_list(body, state),
+        Ok(None) => Ok(Flow::Status(0)),
+        Err(e) => {
+            eprintln!("{}: {e}", "Error".red().bold());
+            Ok(Flow::Status(1))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shell::Shell;
+
+    // Runs `line` and returns what it left in $out
+    fn out(line: &str) -> String {
+        let mut shell = Shell::new();
+        shell.run_line(line).unwrap();
+        shell.var("out").unwrap_or_default()
+    }
+
+    #[test]
+    fn if_takes_the_first_true_branch() {
+        assert_eq!(out("if true; then out=a; else out=b; fi"), "a");
+        assert_eq!(out("if false; then out=a; else out=b; fi"), "b");
+        assert_eq!(
+            out("x=2; if [ $x = 1 ]; then out=a; elif [ $x = 2 ]; then out=b; else out=c; fi"),
+            "b"
+        );
+        assert_eq!(
+            out("out=z; if false; then out=a; elif false; then out=b; fi"),
+            "z"
+        );
+    }
+
+    #[test]
+    fn while_and_until_loop_on_their_condition() {
+        assert_eq!(
+            out("i=0; out=; while [ $i -lt 3 ]; do out=$out$i; i=$((i + 1)); done"),
+            "012"
+        );
+        assert_eq!(
+            out("i=0; out=; until [ $i -eq 3 ]; do out=$out$i; i=$((i + 1)); done"),
+            "012"
+        );
+        let mut shell = Shell::new();
+        shell.run_line("while false; do :; done").unwrap();
+        assert_eq!(shell.last_status(), 0);
+    }
+
+    #[test]
+    fn for_iterates_over_words_and_parameters() {
+        assert_eq!(
+            out("out=; for x in a 'b c' d; do out=$out[$x]; done"),
+            "[a][b c][d]"
+        );
+        assert_eq!(
+            out("f() { out=; for x; do out=$out[$x]; done; }; f 1 2"),
+            "[1][2]"
+        );
+        assert_eq!(out("out=none; for x in; do out=$x; done"), "none");
+    }
+
+    #[test]
+    fn case_runs_the_first_matching_pattern() {
+        assert_eq!(
+            out("x=main.rs; case $x in *.go) out=go;; *.rs|*.toml) out=rust;; *) out=other;; esac"),
+            "rust"
+        );
+        assert_eq!(
+            out("case abc in a) out=a;; *) out=default;; esac"),
+            "default"
+        );
+        assert_eq!(out("out=z; case abc in x) out=x;; esac"), "z");
+        // A quoted pattern matches literally
+        assert_eq!(out("case '*' in '*') out=star;; *) out=any;; esac"), "star");
+    }
+
+    #[test]
+    fn break_and_continue_take_a_count() {
+        assert_eq!(
+            out("out=; for i in 1 2 3 4; do [ $i = 3 ] && break; out=$out$i; done"),
+            "12"
+        );
+        assert_eq!(
+            out("out=; for i in 1 2 3 4; do [ $i = 2 ] && continue; out=$out$i; done"),
+            "134"
+        );
+        assert_eq!(
+            out(
+                "out=; for i in 1 2; do for j in a b; do [ $j = b ] && break 2; out=$out$i$j; done; done"
+            ),
+            "1a"
+        );
+        assert_eq!(
+            out(
+                "out=; for i in 1 2; do for j in a b; do [ $j = a ] && continue 2; out=$out$i$j; done; out=never; done"
+            ),
+            ""
+        );
+    }
+}
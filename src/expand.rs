@@ -0,0 +1,877 @@
+use crate::arith::{ArithError, evaluate};
+use crate::ast::Word;
+use crate::brace::expand_braces;
+use crate::commands::capture_output;
+use crate::glob::{expand_pathname, pattern_matches};
+use crate::state::{Jump, ShellState};
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+pub fn expand_tilde(path: &str) -> String {
+    if path == "~" {
+        dirs::home_dir()
+            .map(|home| home.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string())
+    } else if path.starts_with("~/") {
+        dirs::home_dir()
+            .map(|home| home.join(&path[2..]).to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string())
+    } else {
+        path.to_string()
+    }
+}
+
+fn is_special_parameter(c: char) -> bool {
+    matches!(c, '?' | '$' | '!' | '#' | '@' | '*') || c.is_ascii_digit()
+}
+
+// The length of the parameter name at the start of a `${...}` body
+fn parameter_name_len(body: &str) -> usize {
+    let mut chars = body.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => body
+            .find(|c: char| !c.is_alphanumeric() && c != '_')
+            .unwrap_or(body.len()),
+        Some(c) if c.is_ascii_digit() => body
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(body.len()),
+        Some(c) if is_special_parameter(c) => 1,
+        _ => 0,
+    }
+}
+
+// Reads the body of a `${...}` up to its closing brace, or None when the
+// brace is never closed
+fn read_braced(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut body = String::new();
+    let mut quote = None;
+
+    while let Some(c) = chars.next() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some('\'') => {}
+            Some(_) | None if c == '\\' => {
+                body.push(c);
+                body.extend(chars.next());
+                continue;
+            }
+            Some(_) => {}
+            None => match c {
+                '}' => return Some(body),
+                '\'' | '"' => quote = Some(c),
+                '$' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    body.push_str("${");
+                    body.push_str(&read_braced(chars)?);
+                    body.push('}');
+                    continue;
+                }
+                '$' if chars.peek() == Some(&'(') => {
+                    chars.next();
+                    body.push_str("$(");
+                    body.push_str(&read_substitution(chars));
+                    body.push(')');
+                    continue;
+                }
+                _ => {}
+            },
+        }
+        body.push(c);
+    }
+
+    None
+}
+
+// Char offsets into `text` that `pattern` matches, tried shortest first
+// unless `longest` is set
+fn match_prefix(pattern: &str, text: &[char], longest: bool) -> Option<usize> {
+    let matches = |end: &usize| pattern_matches(pattern, &text[..*end].iter().collect::<String>());
+    if longest {
+        (0..=text.len()).rev().find(matches)
+    } else {
+        (0..=text.len()).find(matches)
+    }
+}
+
+fn match_suffix(pattern: &str, text: &[char], longest: bool) -> Option<usize> {
+    let matches =
+        |start: &usize| pattern_matches(pattern, &text[*start..].iter().collect::<String>());
+    if longest {
+        (0..=text.len()).find(matches)
+    } else {
+        (0..=text.len()).rev().find(matches)
+    }
+}
+
+// `${NAME/pattern/replacement}` and its `//`, `/#` and `/%` forms. Each
+// match is the longest one starting at its position.
+fn replace_pattern(value: &str, op: &str, pattern: &str, replacement: &str) -> String {
+    let text: Vec<char> = value.chars().collect();
+    let rest = |range: &[char]| range.iter().collect::<String>();
+
+    match op {
+        "/#" => {
+            return match match_prefix(pattern, &text, true) {
+                Some(end) => format!("{replacement}{}", rest(&text[end..])),
+                None => value.to_string(),
+            };
+        }
+        "/%" => {
+            return match match_suffix(pattern, &text, true) {
+                Some(start) => format!("{}{replacement}", rest(&text[..start])),
+                None => value.to_string(),
+            };
+        }
+        _ => {}
+    }
+
+    let mut result = String::new();
+    let mut replaced = false;
+    let mut i = 0;
+    while i < text.len() {
+        if (op == "//" || !replaced)
+            && let Some(len) = match_prefix(pattern, &text[i..], true).filter(|&len| len > 0)
+        {
+            result.push_str(replacement);
+            replaced = true;
+            i += len;
+        } else {
+            result.push(text[i]);
+            i += 1;
+        }
+    }
+    result
+}
+
+// Splits `offset:length` at the first colon outside parentheses
+fn split_length(spec: &str) -> (&str, Option<&str>) {
+    let mut depth = 0;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ':' if depth == 0 => return (&spec[..i], Some(&spec[i + 1..])),
+            _ => {}
+        }
+    }
+    (spec, None)
+}
+
+// `${NAME:offset}` and `${NAME:offset:length}`, whose offset and length are
+// arithmetic expressions. A negative offset counts from the end and a
+// negative length stops short of it.
+fn substring(value: &str, spec: &str, state: &mut ShellState) -> Result<String, ExpandError> {
+    let text: Vec<char> = value.chars().collect();
+    let len = text.len() as i64;
+    let (offset, length) = split_length(spec);
+    let offset = evaluate_arithmetic(&Word::new(offset), state)?;
+    let length = match length {
+        Some(length) => Some(evaluate_arithmetic(&Word::new(length), state)?),
+        None => None,
+    };
+
+    let start = if offset < 0 { len + offset } else { offset }.clamp(0, len);
+    let end = match length {
+        None => len,
+        Some(length) if length < 0 => len + length,
+        Some(length) => start + length,
+    }
+    .clamp(start, len);
+    Ok(text[start as usize..end as usize].iter().collect())
+}
+
+fn expand_operand(operand: &str, state: &mut ShellState) -> Result<String, ExpandError> {
+    expand_word(&Word::new(operand), state)
+}
+
+fn expand_braced(body: &str, state: &mut ShellState) -> Result<String, ExpandError> {
+    let bad_substitution = || ExpandError::BadSubstitution(format!("${{{body}}}"));
+
+    if let Some(name) = body.strip_prefix('#')
+        && !name.is_empty()
+    {
+        if parameter_name_len(name) != name.len() {
+            return Err(bad_substitution());
+        }
+        let value = state.lookup(name).unwrap_or_default();
+        return Ok(value.chars().count().to_string());
+    }
+
+    let (name, op) = body.split_at(parameter_name_len(body));
+    if name.is_empty() {
+        return Err(bad_substitution());
+    }
+    let value = state.lookup(name);
+    let set = value.is_some();
+    let value = value.unwrap_or_default();
+
+    // With a colon an empty value counts as unset
+    let (missing, op) = match op.strip_prefix(':') {
+        Some(rest) if rest.starts_with(['-', '=', '?', '+']) => (value.is_empty(), rest),
+        Some(spec) => return substring(&value, spec, state),
+        None => (!set, op),
+    };
+
+    let operator = op.chars().next();
+    let operand = op.get(1..).unwrap_or_default();
+    match operator {
+        None => Ok(value),
+        Some('-') if missing => expand_operand(operand, state),
+        Some('=') if missing => {
+            if parameter_name_len(name) == 1 && !name.starts_with(|c: char| c.is_alphabetic()) {
+                return Err(ExpandError::Parameter(
+                    name.to_string(),
+                    "cannot assign in this way".to_string(),
+                ));
+            }
+            let value = expand_operand(operand, state)?;
+            state
+                .set_var(name, &value)
+                .map_err(|e| ExpandError::Parameter(name.to_string(), e.to_string()))?;
+            Ok(value)
+        }
+        Some('?') if missing => {
+            let message = match expand_operand(operand, state)? {
+                message if message.is_empty() => "parameter null or not set".to_string(),
+                message => message,
+            };
+            Err(ExpandError::Parameter(name.to_string(), message))
+        }
+        Some('+') if missing => Ok(String::new()),
+        Some('+') => expand_operand(operand, state),
+        Some('-' | '=' | '?') => Ok(value),
+        Some('#' | '%') => {
+            let longest = operand.starts_with(operator.unwrap_or_default());
+            let pattern = if longest { &operand[1..] } else { operand };
+            let pattern = expand_pattern(&Word::new(pattern), state)?;
+            let text: Vec<char> = value.chars().collect();
+            let trimmed = if operator == Some('#') {
+                match_prefix(&pattern, &text, longest).map(|end| &text[end..])
+            } else {
+                match_suffix(&pattern, &text, longest).map(|start| &text[..start])
+            };
+            Ok(trimmed.unwrap_or(&text).iter().collect())
+        }
+        Some('/') => {
+            let (op, rest) = match operand.chars().next() {
+                Some(c @ ('/' | '#' | '%')) => (&op[..2], &operand[c.len_utf8()..]),
+                _ => ("/", operand),
+            };
+            let (pattern, replacement) = split_replacement(rest);
+            let pattern = expand_pattern(&Word::new(pattern), state)?;
+            let replacement = expand_operand(replacement, state)?;
+            Ok(replace_pattern(&value, op, &pattern, &replacement))
+        }
+        _ => Err(bad_substitution()),
+    }
+}
+
+// Splits `pattern/replacement` at the first slash that is not escaped or
+// quoted
+fn split_replacement(text: &str) -> (&str, &str) {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match quote {
+            _ if escaped => escaped = false,
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None => match c {
+                '\\' => escaped = true,
+                '\'' | '"' => quote = Some(c),
+                '/' => return (&text[..i], &text[i + 1..]),
+                _ => {}
+            },
+        }
+    }
+    (text, "")
+}
+
+fn expand_parameter(
+    chars: &mut Peekable<Chars>,
+    state: &mut ShellState,
+) -> Result<String, ExpandError> {
+    match chars.peek() {
+        Some('{') => {
+            chars.next(); // consume '{'
+            let result = match read_braced(chars) {
+                Some(body) => expand_braced(&body, state),
+                None => Err(ExpandError::BadSubstitution("${".to_string())),
+            };
+            abort_on_error(result, state)
+        }
+        Some(&c) if c.is_alphabetic() || c == '_' => {
+            let mut var_name = String::new();
+
+            while let Some(&next_char) = chars.peek() {
+                if next_char.is_alphanumeric() || next_char == '_' {
+                    var_name.push(next_char);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            Ok(state.lookup(&var_name).unwrap_or_default())
+        }
+        Some(&c) if is_special_parameter(c) => {
+            chars.next();
+            Ok(state.lookup(&c.to_string()).unwrap_or_default())
+        }
+        _ => Ok("$".to_string()),
+    }
+}
+
+#[derive(Debug)]
+pub enum ExpandError {
+    NoMatch(String),
+    BadSubstitution(String),
+    Parameter(String, String),
+    Arithmetic(ArithError),
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpandError::NoMatch(pattern) => write!(f, "no match: {pattern}"),
+            ExpandError::BadSubstitution(text) => write!(f, "{text}: bad substitution"),
+            ExpandError::Parameter(name, message) => write!(f, "{name}: {message}"),
+            ExpandError::Arithmetic(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ExpandError {}
+
+// Collects the fields a word expands to. The results of unquoted parameter
+// expansions and command substitutions are split on IFS; everything else
+// extends the current field.
+// Alongside the text each field keeps a glob pattern and a regex in which
+// quoted characters are escaped.
+struct Field {
+    text: String,
+    pattern: String,
+    regex: String,
+    globbing: bool,
+}
+
+#[derive(Default)]
+struct Fields {
+    fields: Vec<Field>,
+    current: String,
+    pattern: String,
+    regex: String,
+    globbing: bool,
+    started: bool,
+}
+
+impl Fields {
+    fn push(&mut self, c: char, quoted: bool) {
+        self.current.push(c);
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') && (quoted || c == '\\') {
+            self.pattern.push('\\');
+        } else if matches!(c, '*' | '?' | '[') {
+            self.globbing = true;
+        }
+        self.pattern.push(c);
+        if quoted {
+            self.regex
+                .push_str(&regex::escape(c.encode_utf8(&mut [0; 4])));
+        } else {
+            self.regex.push(c);
+        }
+        self.started = true;
+    }
+
+    fn push_str(&mut self, text: &str, quoted: bool) {
+        for c in text.chars() {
+            self.push(c, quoted);
+        }
+        self.started = true;
+    }
+
+    fn push_split(&mut self, text: &str, separators: &str) {
+        for c in text.chars() {
+            if separators.contains(c) {
+                self.finish();
+            } else {
+                self.push(c, false);
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.started {
+            self.fields.push(Field {
+                text: std::mem::take(&mut self.current),
+                pattern: std::mem::take(&mut self.pattern),
+                regex: std::mem::take(&mut self.regex),
+                globbing: self.globbing,
+            });
+            self.globbing = false;
+            self.started = false;
+        }
+    }
+
+    fn into_fields(mut self) -> Vec<Field> {
+        self.finish();
+        self.fields
+    }
+}
+
+fn read_substitution(chars: &mut Peekable<Chars>) -> String {
+    let mut body = String::new();
+    let mut depth = 1;
+    let mut quote = None;
+
+    while let Some(c) = chars.next() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some('\'') => {}
+            Some(_) | None if c == '\\' => {
+                body.push(c);
+                body.extend(chars.next());
+                continue;
+            }
+            Some(_) => {}
+            None => match c {
+                '\'' | '"' | '`' => quote = Some(c),
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            },
+        }
+        body.push(c);
+    }
+
+    body
+}
+
+// Reads up to `max` more digits onto `value` and returns the character
+// with that code, or None when there were no digits at all
+fn read_digits(
+    chars: &mut Peekable<Chars>,
+    radix: u32,
+    max: usize,
+    mut value: u32,
+    mut count: usize,
+) -> Option<char> {
+    while count < max
+        && let Some(digit) = chars.peek().and_then(|c| c.to_digit(radix))
+    {
+        value = value * radix + digit;
+        count += 1;
+        chars.next();
+    }
+    (count > 0).then(|| char::from_u32(value)).flatten()
+}
+
+// Decodes the body of a `$'...'` string
+fn read_ansi_quoted(chars: &mut Peekable<Chars>) -> String {
+    let mut text = String::new();
+
+    while let Some(c) = chars.next() {
+        if c == '\'' {
+            break;
+        }
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+
+        let decoded = match chars.next() {
+            Some('n') => Some('\n'),
+            Some('t') => Some('\t'),
+            Some('r') => Some('\r'),
+            Some('a') => Some('\x07'),
+            Some('b') => Some('\x08'),
+            Some('e' | 'E') => Some('\x1b'),
+            Some('f') => Some('\x0c'),
+            Some('v') => Some('\x0b'),
+            Some(c @ ('\\' | '\'' | '"' | '?')) => Some(c),
+            Some('x') => read_digits(chars, 16, 2, 0, 0),
+            Some('u') => read_digits(chars, 16, 4, 0, 0),
+            Some('U') => read_digits(chars, 16, 8, 0, 0),
+            Some('c') => chars
+                .next()
+                .map(|c| char::from(c.to_ascii_uppercase() as u8 ^ 0x40)),
+            Some(c @ '0'..='7') => read_digits(chars, 8, 3, c.to_digit(8).unwrap_or(0), 1),
+            Some(other) => {
+                text.push('\\');
+                Some(other)
+            }
+            None => Some('\\'),
+        };
+        text.extend(decoded);
+    }
+
+    text
+}
+
+fn read_backquoted(chars: &mut Peekable<Chars>) -> String {
+    let mut body = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '`' => break,
+            '\\' if matches!(chars.peek(), Some('`' | '\\' | '$')) => {
+                body.extend(chars.next());
+            }
+            _ => body.push(c),
+        }
+    }
+
+    body
+}
+
+fn starts_arithmetic(chars: &Peekable<Chars>) -> bool {
+    let mut next = chars.clone();
+    next.next() == Some('(') && next.next() == Some('(')
+}
+
+// `$((...))`, given what follows the `$`
+fn expand_arithmetic(
+    chars: &mut Peekable<Chars>,
+    state: &mut ShellState,
+) -> Result<String, ExpandError> {
+    chars.next();
+    let body = read_substitution(chars);
+    let expression = body.strip_prefix('(').unwrap_or(&body);
+    let expression = expression.strip_suffix(')').unwrap_or(expression);
+    let value = evaluate_arithmetic(&Word::new(expression), state);
+    Ok(abort_on_error(value, state)?.to_string())
+}
+
+// An error in `${...}` or `$((...))` abandons the rest of the input, as a
+// failed `${NAME:?}` does, instead of running the command without the value
+fn abort_on_error<T>(
+    result: Result<T, ExpandError>,
+    state: &mut ShellState,
+) -> Result<T, ExpandError> {
+    if result.is_err() {
+        state.jump = Some(Jump::Abort);
+    }
+    result
+}
+
+// The expression is expanded like a word before it is evaluated
+pub fn evaluate_arithmetic(expression: &Word, state: &mut ShellState) -> Result<i64, ExpandError> {
+    let expression = expand_word(expression, state)?;
+    evaluate(&expression, state).map_err(ExpandError::Arithmetic)
+}
+
+fn expand_fields(
+    word: &Word,
+    state: &mut ShellState,
+    split: bool,
+) -> Result<Vec<Field>, ExpandError> {
+    let raw = word.raw.as_str();
+    let mut fields = Fields::default();
+    let rest = match raw.find('/') {
+        Some(slash) if &raw[..slash] == "~" => {
+            fields.push_str(&expand_tilde("~"), true);
+            &raw[slash..]
+        }
+        None if raw == "~" => {
+            fields.push_str(&expand_tilde("~"), true);
+            ""
+        }
+        _ => raw,
+    };
+
+    let separators = state.lookup("IFS").unwrap_or_else(|| " \t\n".to_string());
+    let mut chars = rest.chars().peekable();
+    let mut in_double_quotes = false;
+    // Quotes that held nothing but a "$@" with no parameters leave no field
+    // behind, unless the word had something else
+    let mut started_before_quote = false;
+    let mut empty_at = false;
+
+    while let Some(c) = chars.next() {
+        if c == '$' && starts_arithmetic(&chars) {
+            let value = expand_arithmetic(&mut chars, state)?;
+            fields.push_str(&value, in_double_quotes);
+            continue;
+        }
+
+        let substitution = match c {
+            '$' if chars.peek() == Some(&'(') => {
+                chars.next();
+                Some(read_substitution(&mut chars))
+            }
+            '`' => Some(read_backquoted(&mut chars)),
+            _ => None,
+        };
+        if let Some(body) = substitution {
+            let output = capture_output(&body, state);
+            if split && !in_double_quotes {
+                fields.push_split(&output, &separators);
+            } else {
+                fields.push_str(&output, in_double_quotes);
+            }
+            continue;
+        }
+
+        match c {
+            '\'' if !in_double_quotes => {
+                fields.started = true;
+                for c in chars.by_ref() {
+                    if c == '\'' {
+                        break;
+                    }
+                    fields.push(c, true);
+                }
+            }
+            '"' if !in_double_quotes => {
+                started_before_quote = fields.started;
+                empty_at = false;
+                fields.started = true;
+                in_double_quotes = true;
+            }
+            '"' => {
+                if empty_at && !started_before_quote && fields.current.is_empty() {
+                    fields.started = false;
+                }
+                in_double_quotes = false;
+            }
+            // Inside double quotes a backslash only escapes the characters
+            // that would otherwise mean something there
+            '\\' if in_double_quotes => match chars.peek() {
+                Some(&next @ ('$' | '`' | '"' | '\\')) => {
+                    chars.next();
+                    fields.push(next, true);
+                }
+                _ => fields.push(c, true),
+            },
+            '\\' => fields.push(chars.next().unwrap_or(c), true),
+            '$' if !in_double_quotes && chars.peek() == Some(&'\'') => {
+                chars.next();
+                fields.started = true;
+                fields.push_str(&read_ansi_quoted(&mut chars), true);
+            }
+            // "$@", and $@ and $* outside quotes, give each positional
+            // parameter a field of its own
+            '$' if split
+                && (chars.peek() == Some(&'@')
+                    || (chars.peek() == Some(&'*') && !in_double_quotes)) =>
+            {
+                chars.next();
+                empty_at |= in_double_quotes && state.positional.is_empty();
+                for (i, arg) in state.positional.iter().enumerate() {
+                    if i > 0 {
+                        fields.finish();
+                    }
+                    fields.push_str(arg, in_double_quotes);
+                }
+            }
+            '$' => {
+                let value = expand_parameter(&mut chars, state)?;
+                if split && !in_double_quotes {
+                    fields.push_split(&value, &separators);
+                } else {
+                    fields.push_str(&value, in_double_quotes);
+                }
+            }
+            _ => fields.push(c, in_double_quotes),
+        }
+    }
+
+    Ok(fields.into_fields())
+}
+
+// Expands the body of a here-document. Quotes are ordinary characters
+// there, and a backslash only escapes `$`, `` ` ``, itself and a newline.
+pub fn expand_here_doc(body: &str, state: &mut ShellState) -> Result<String, ExpandError> {
+    let mut text = String::new();
+    let mut chars = body.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some('\n') => {
+                    chars.next();
+                }
+                Some(&next @ ('$' | '`' | '\\')) => {
+                    chars.next();
+                    text.push(next);
+                }
+                _ => text.push(c),
+            },
+            '$' if starts_arithmetic(&chars) => {
+                text.push_str(&expand_arithmetic(&mut chars, state)?);
+            }
+            '$' if chars.peek() == Some(&'(') => {
+                chars.next();
+                let body = read_substitution(&mut chars);
+                text.push_str(&capture_output(&body, state));
+            }
+            '`' => {
+                let body = read_backquoted(&mut chars);
+                text.push_str(&capture_output(&body, state));
+            }
+            '$' => text.push_str(&expand_parameter(&mut chars, state)?),
+            _ => text.push(c),
+        }
+    }
+
+    Ok(text)
+}
+
+pub fn expand_word(word: &Word, state: &mut ShellState) -> Result<String, ExpandError> {
+    let fields = expand_fields(word, state, false)?;
+    Ok(fields.into_iter().map(|field| field.text).collect())
+}
+
+// Expands a word for use as a pattern, with quoted characters escaped so
+// that they only match themselves.
+pub fn expand_pattern(word: &Word, state: &mut ShellState) -> Result<String, ExpandError> {
+    let fields = expand_fields(word, state, false)?;
+    Ok(fields.into_iter().map(|field| field.pattern).collect())
+}
+
+// Expands a word for use as a regex, with quoted characters escaped
+pub fn expand_regex(word: &Word, state: &mut ShellState) -> Result<String, ExpandError> {
+    let fields = expand_fields(word, state, false)?;
+    Ok(fields.into_iter().map(|field| field.regex).collect())
+}
+
+// Expands words into command arguments, starting with brace expansion and
+// ending with pathname expansion of unquoted pattern characters.
+pub fn expand_words(words: &[Word], state: &mut ShellState) -> Result<Vec<String>, ExpandError> {
+    let mut result = Vec::new();
+
+    for word in words.iter().flat_map(|word| expand_braces(&word.raw)) {
+        for field in expand_fields(&Word::new(word), state, true)? {
+            if !field.globbing {
+                result.push(field.text);
+                continue;
+            }
+
+            let matches = expand_pathname(&field.pattern);
+            if !matches.is_empty() {
+                result.extend(matches);
+            } else if state.options.failglob {
+                return Err(ExpandError::NoMatch(field.text));
+            } else if !state.options.nullglob {
+                result.push(field.text);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> ShellState {
+        let mut state = ShellState::new();
+        state.set_var("path", "dir/file.tar.gz").unwrap();
+        state.set_var("empty", "").unwrap();
+        state.set_var("words", "one two  three").unwrap();
+        state.unset_var("unset").unwrap();
+        state
+    }
+
+    fn expand(word: &str, state: &mut ShellState) -> String {
+        expand_word(&Word::new(word), state).unwrap()
+    }
+
+    #[test]
+    fn defaults_and_alternatives() {
+        let mut state = state();
+        assert_eq!(expand("${unset:-d}", &mut state), "d");
+        assert_eq!(expand("${empty:-d}", &mut state), "d");
+        assert_eq!(expand("${empty-d}", &mut state), "");
+        assert_eq!(expand("${unset-d}", &mut state), "d");
+        assert_eq!(expand("${path:+set}", &mut state), "set");
+        assert_eq!(expand("${unset:+set}", &mut state), "");
+        assert_eq!(expand("${unset:=new}", &mut state), "new");
+        assert_eq!(state.lookup("unset").as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn missing_value_is_an_error() {
+        let mut state = state();
+        let error = expand_word(&Word::new("${unset:?gone}"), &mut state).unwrap_err();
+        assert_eq!(error.to_string(), "unset: gone");
+        assert_eq!(state.jump, Some(Jump::Abort));
+    }
+
+    #[test]
+    fn arithmetic_and_substitution_errors_abort() {
+        let mut state = state();
+        let error = expand_word(&Word::new("$((1/0))"), &mut state).unwrap_err();
+        assert_eq!(error.to_string(), "1/0: division by 0");
+        assert_eq!(state.jump, Some(Jump::Abort));
+
+        state.jump = None;
+        assert!(expand_word(&Word::new("${#path:?}"), &mut state).is_err());
+        assert_eq!(state.jump, Some(Jump::Abort));
+    }
+
+    #[test]
+    fn length_and_trimming() {
+        let mut state = state();
+        assert_eq!(expand("${#path}", &mut state), "15");
+        assert_eq!(expand("${path#*.}", &mut state), "tar.gz");
+        assert_eq!(expand("${path##*.}", &mut state), "gz");
+        assert_eq!(expand("${path%.*}", &mut state), "dir/file.tar");
+        assert_eq!(expand("${path%%.*}", &mut state), "dir/file");
+        assert_eq!(expand("${path#nomatch}", &mut state), "dir/file.tar.gz");
+    }
+
+    #[test]
+    fn replacement() {
+        let mut state = state();
+        assert_eq!(expand("${path/./-}", &mut state), "dir/file-tar.gz");
+        assert_eq!(expand("${path//./-}", &mut state), "dir/file-tar-gz");
+        assert_eq!(expand("${path/#dir/top}", &mut state), "top/file.tar.gz");
+        assert_eq!(expand("${path/%gz/xz}", &mut state), "dir/file.tar.xz");
+        assert_eq!(expand("${path//.}", &mut state), "dir/filetargz");
+    }
+
+    #[test]
+    fn substrings() {
+        let mut state = state();
+        state.set_var("n", "4").unwrap();
+        assert_eq!(expand("${path:4}", &mut state), "file.tar.gz");
+        assert_eq!(expand("${path:$n:4}", &mut state), "file");
+        assert_eq!(expand("${path:2*2:n}", &mut state), "file");
+        assert_eq!(expand("${path: -2}", &mut state), "gz");
+        assert_eq!(expand("${path:(-6):3}", &mut state), "tar");
+        assert_eq!(expand("${path:4:-3}", &mut state), "file.tar");
+    }
+
+    #[test]
+    fn unquoted_expansions_are_split() {
+        let mut state = state();
+        let words = [Word::new("$words"), Word::new("\"$words\"")];
+        assert_eq!(
+            expand_words(&words, &mut state).unwrap(),
+            ["one", "two", "three", "one two  three"]
+        );
+        let words = [Word::new("${unset:-a b}")];
+        assert_eq!(expand_words(&words, &mut state).unwrap(), ["a", "b"]);
+    }
+
+    #[test]
+    fn quoted_at_without_parameters_is_no_field() {
+        let mut state = state();
+        state.positional.clear();
+        let words = [Word::new("\"$@\"")];
+        assert!(expand_words(&words, &mut state).unwrap().is_empty());
+        let words = [Word::new("x\"$@\""), Word::new("\"\"")];
+        assert_eq!(expand_words(&words, &mut state).unwrap(), ["x", ""]);
+        state.positional = vec!["a b".to_string(), "c".to_string()];
+        let words = [Word::new("\"$@\"")];
+        assert_eq!(expand_words(&words, &mut state).unwrap(), ["a b", "c"]);
+    }
+}
@@ -0,0 +1,393 @@
+use crate::state::ShellState;
+use colored::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// How long the prompt waits for git before it shows the last known status
+const WAIT: Duration = Duration::from_millis(50);
+// git is stopped after this long and only the branch is shown
+const TIMEOUT: Duration = Duration::from_secs(2);
+// How often the line editor is interrupted until it has stopped to draw a
+// newer segment, as a signal that arrives before it waits for a key is lost
+const INTERRUPT_RETRY: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct GitStatus {
+    // The branch name, or the abbreviated commit of a detached HEAD
+    branch: String,
+    detached: bool,
+    ahead: u32,
+    behind: u32,
+    staged: u32,
+    dirty: u32,
+    conflicted: u32,
+    untracked: u32,
+    // A rebase, merge or similar that is stopped partway
+    operation: Option<String>,
+}
+
+impl fmt::Display for GitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.detached {
+            write!(f, "{}", format!("({})", self.branch).yellow())?;
+        } else {
+            write!(f, "{}", self.branch.magenta())?;
+        }
+        if let Some(operation) = &self.operation {
+            write!(f, "|{}", operation.red().bold())?;
+        }
+        if self.ahead > 0 || self.behind > 0 {
+            write!(f, " ")?;
+        }
+        if self.ahead > 0 {
+            write!(f, "{}", format!("↑{}", self.ahead).cyan())?;
+        }
+        if self.behind > 0 {
+            write!(f, "{}", format!("↓{}", self.behind).cyan())?;
+        }
+        let counts = [
+            ('+', self.staged, Color::Green),
+            ('~', self.dirty, Color::Yellow),
+            ('!', self.conflicted, Color::Red),
+            ('?', self.untracked, Color::BrightBlack),
+        ];
+        for (symbol, count, color) in counts {
+            if count > 0 {
+                write!(f, " {}", format!("{symbol}{count}").color(color))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The work tree and git directory of the repository that contains `dir`
+fn find_repository(dir: &Path) -> Option<(PathBuf, PathBuf)> {
+    for root in dir.ancestors() {
+        let dot_git = root.join(".git");
+        if dot_git.is_dir() {
+            return Some((root.to_path_buf(), dot_git));
+        }
+        // Linked work trees and submodules have a file naming the directory
+        if let Ok(text) = fs::read_to_string(&dot_git)
+            && let Some(path) = text.trim().strip_prefix("gitdir: ")
+        {
+            return Some((root.to_path_buf(), root.join(path)));
+        }
+    }
+    None
+}
+
+// The branch read straight from HEAD, shown until git has answered
+fn read_head(git_dir: &Path) -> GitStatus {
+    let head = fs::read_to_string(git_dir.join("HEAD")).unwrap_or_default();
+    let head = head.trim();
+    match head.strip_prefix("ref: ") {
+        Some(reference) => GitStatus {
+            branch: reference.trim_start_matches("refs/heads/").to_string(),
+            ..GitStatus::default()
+        },
+        None => GitStatus {
+            branch: head.chars().take(7).collect(),
+            detached: true,
+            ..GitStatus::default()
+        },
+    }
+}
+
+fn operation(git_dir: &Path) -> Option<String> {
+    let read = |name: &str| {
+        fs::read_to_string(git_dir.join(name))
+            .ok()
+            .map(|text| text.trim().to_string())
+    };
+
+    let rebase = if git_dir.join("rebase-merge").is_dir() {
+        Some((
+            "REBASE",
+            read("rebase-merge/msgnum"),
+            read("rebase-merge/end"),
+        ))
+    } else if git_dir.join("rebase-apply").is_dir() {
+        let name = if git_dir.join("rebase-apply/applying").exists() {
+            "AM"
+        } else {
+            "REBASE"
+        };
+        Some((name, read("rebase-apply/next"), read("rebase-apply/last")))
+    } else {
+        None
+    };
+    if let Some((name, step, total)) = rebase {
+        return Some(match (step, total) {
+            (Some(step), Some(total)) => format!("{name} {step}/{total}"),
+            _ => name.to_string(),
+        });
+    }
+
+    [
+        ("MERGE_HEAD", "MERGING"),
+        ("CHERRY_PICK_HEAD", "CHERRY-PICKING"),
+        ("REVERT_HEAD", "REVERTING"),
+        ("BISECT_LOG", "BISECTING"),
+    ]
+    .iter()
+    .find(|(file, _)| git_dir.join(file).exists())
+    .map(|(_, name)| name.to_string())
+}
+
+// Reads the output of `git status --porcelain=v2 --branch`
+fn parse_status(output: &str) -> GitStatus {
+    let mut status = GitStatus::default();
+    let mut commit = "";
+
+    for line in output.lines() {
+        let mut fields = line.split(' ');
+        match fields.next() {
+            Some("#") => match (fields.next(), fields.next()) {
+                (Some("branch.oid"), Some(oid)) => commit = oid,
+                (Some("branch.head"), Some("(detached)")) => status.detached = true,
+                (Some("branch.head"), Some(head)) => status.branch = head.to_string(),
+                (Some("branch.ab"), Some(ahead)) => {
+                    status.ahead = ahead.trim_start_matches('+').parse().unwrap_or(0);
+                    status.behind = fields
+                        .next()
+                        .and_then(|behind| behind.trim_start_matches('-').parse().ok())
+                        .unwrap_or(0);
+                }
+                _ => {}
+            },
+            Some("1" | "2") => {
+                let mut xy = fields.next().unwrap_or("..").chars();
+                status.staged += (xy.next() != Some('.')) as u32;
+                status.dirty += (xy.next() != Some('.')) as u32;
+            }
+            Some("u") => status.conflicted += 1,
+            Some("?") => status.untracked += 1,
+            _ => {}
+        }
+    }
+
+    if status.detached {
+        status.branch = commit.chars().take(7).collect();
+    }
+    status
+}
+
+fn run_status(root: &Path, git_dir: &Path, environment: &[(String, String)]) -> GitStatus {
+    let mut status = read_head(git_dir);
+    let child = Command::new("git")
+        .args([
+            "--no-optional-locks",
+            "status",
+            "--porcelain=v2",
+            "--branch",
+        ])
+        .env_clear()
+        .envs(environment.iter().cloned())
+        .current_dir(root)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+
+    if let Ok(child) = child {
+        let pid = child.id() as i32;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || sender.send(child.wait_with_output()));
+        match receiver.recv_timeout(TIMEOUT) {
+            Ok(Ok(output)) if output.status.success() => {
+                status = parse_status(&String::from_utf8_lossy(&output.stdout));
+            }
+            Err(RecvTimeoutError::Timeout) => unsafe {
+                libc::kill(pid, libc::SIGKILL);
+            },
+            _ => {}
+        }
+    }
+
+    status.operation = operation(git_dir);
+    status
+}
+
+#[derive(Default)]
+struct Segments {
+    // The last segment computed for each repository, by work tree
+    latest: HashMap<PathBuf, String>,
+    // Repositories that git is running for, and those where something may
+    // have changed since it started
+    running: HashSet<PathBuf>,
+    stale: HashSet<PathBuf>,
+    // The repository and segment in the prompt being edited
+    shown: Option<(PathBuf, String)>,
+    // The thread reading a line at the prompt, and whether it has been asked
+    // to stop so that the prompt can be drawn again
+    editor: Option<libc::pthread_t>,
+    redraw: bool,
+}
+
+impl Segments {
+    fn outdated(&self) -> bool {
+        self.shown.as_ref().is_some_and(|(root, shown)| {
+            self.latest.get(root).is_some_and(|latest| latest != shown)
+        })
+    }
+}
+
+// Interrupts the line editor until it stops, when the prompt it is showing
+// has an older segment than git has since given
+fn interrupt_editor(segments: &(Mutex<Segments>, Condvar)) {
+    loop {
+        let mut segments = segments.0.lock().unwrap();
+        segments.redraw |= segments.outdated();
+        match segments.editor {
+            Some(thread) if segments.redraw => unsafe {
+                libc::pthread_kill(thread, libc::SIGINT);
+            },
+            _ => break,
+        }
+        drop(segments);
+        thread::sleep(INTERRUPT_RETRY);
+    }
+}
+
+// The `\g` prompt segment. git runs on a background thread so that a slow
+// repository never holds up the prompt. A result that arrives while a line
+// is being edited interrupts the line editor, which is then started again
+// with the new prompt and the line as it was.
+#[derive(Clone, Default)]
+pub struct GitPrompt {
+    segments: Arc<(Mutex<Segments>, Condvar)>,
+}
+
+impl GitPrompt {
+    fn refresh(&self, root: PathBuf, git_dir: PathBuf, state: &ShellState) {
+        let environment: Vec<(String, String)> = state
+            .environment()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        let segments = Arc::clone(&self.segments);
+
+        thread::spawn(move || {
+            loop {
+                let segment = run_status(&root, &git_dir, &environment).to_string();
+                let (lock, done) = &*segments;
+                let mut segments = lock.lock().unwrap();
+                segments.latest.insert(root.clone(), segment);
+                if segments.stale.remove(&root) {
+                    continue;
+                }
+                segments.running.remove(&root);
+                done.notify_all();
+                break;
+            }
+            interrupt_editor(&segments);
+        });
+    }
+
+    // The segment for the repository the shell is in, or nothing outside
+    // one. Starts git and waits a moment for it, then falls back to the last
+    // segment seen for the repository or the branch alone.
+    pub fn segment(&self, state: &ShellState) -> String {
+        let (lock, done) = &*self.segments;
+        let Some((root, git_dir)) = find_repository(&state.cwd) else {
+            lock.lock().unwrap().shown = None;
+            return String::new();
+        };
+
+        let mut segments = lock.lock().unwrap();
+        if segments.running.contains(&root) {
+            segments.stale.insert(root.clone());
+        } else {
+            segments.running.insert(root.clone());
+            self.refresh(root.clone(), git_dir.clone(), state);
+        }
+        let (mut segments, _) = done
+            .wait_timeout_while(segments, WAIT, |segments| segments.running.contains(&root))
+            .unwrap();
+
+        let segment = segments
+            .latest
+            .get(&root)
+            .cloned()
+            .unwrap_or_else(|| read_head(&git_dir).to_string());
+        segments.shown = Some((root, segment.clone()));
+        segment
+    }
+
+    // Called once the prompt is no longer being edited
+    pub fn clear_shown(&self) {
+        self.segments.0.lock().unwrap().shown = None;
+    }
+
+    // Called from the thread about to read a line at the prompt, which has
+    // SIGINT handled by the line editor. Returns false when git has answered
+    // since the prompt was drawn and it should be drawn again first.
+    pub fn begin_edit(&self) -> bool {
+        let mut segments = self.segments.0.lock().unwrap();
+        if segments.outdated() {
+            return false;
+        }
+        segments.editor = Some(unsafe { libc::pthread_self() });
+        segments.redraw = false;
+        true
+    }
+
+    // Called once the line editor has returned. Returns true when it was
+    // interrupted to draw the prompt again.
+    pub fn end_edit(&self) -> bool {
+        let mut segments = self.segments.0.lock().unwrap();
+        segments.editor = None;
+        std::mem::take(&mut segments.redraw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_counts_changes() {
+        let output = "\
+# branch.oid 1234567890abcdef
+# branch.head main
+# branch.upstream origin/main
+# branch.ab +2 -1
+1 M. N... 100644 100644 100644 1234 5678 staged.rs
+1 .M N... 100644 100644 100644 1234 5678 dirty.rs
+1 MM N... 100644 100644 100644 1234 5678 both.rs
+2 R. N... 100644 100644 100644 1234 5678 R100 new.rs\told.rs
+u UU N... 100644 100644 100644 100644 1234 5678 9abc conflict.rs
+? untracked.rs
+? another.rs
+";
+        assert_eq!(
+            parse_status(output),
+            GitStatus {
+                branch: "main".to_string(),
+                ahead: 2,
+                behind: 1,
+                staged: 3,
+                dirty: 2,
+                conflicted: 1,
+                untracked: 2,
+                ..GitStatus::default()
+            }
+        );
+    }
+
+    #[test]
+    fn detached_head_shows_the_commit() {
+        let output = "# branch.oid 1234567890abcdef\n# branch.head (detached)\n";
+        let status = parse_status(output);
+        assert!(status.detached);
+        assert_eq!(status.branch, "1234567");
+        assert_eq!((status.ahead, status.behind), (0, 0));
+    }
+}
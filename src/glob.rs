@@ -0,0 +1,265 @@
+use std::fs;
+use std::path::Path;
+
+// Patterns use `*`, `?` and `[...]`; a backslash makes the next character
+// literal. Expansion escapes quoted text this way before matching.
+
+fn class_matches(class: &str, c: char) -> bool {
+    match class {
+        "alpha" => c.is_alphabetic(),
+        "digit" => c.is_ascii_digit(),
+        "alnum" => c.is_alphanumeric(),
+        "upper" => c.is_uppercase(),
+        "lower" => c.is_lowercase(),
+        "space" => c.is_whitespace(),
+        "blank" => c == ' ' || c == '\t',
+        "punct" => c.is_ascii_punctuation(),
+        "xdigit" => c.is_ascii_hexdigit(),
+        "cntrl" => c.is_control(),
+        "print" => !c.is_control(),
+        "graph" => !c.is_control() && !c.is_whitespace(),
+        _ => false,
+    }
+}
+
+// Matches `c` against the bracket expression that starts after a `[`.
+// Returns whether it matched and how many pattern characters the expression
+// used, or None when there is no closing `]` and the `[` is literal.
+fn match_bracket(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = matches!(pattern.first(), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut start = *pattern.get(i)?;
+        if start == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+
+        if start == '['
+            && pattern.get(i + 1) == Some(&':')
+            && let Some(end) = (i + 2..pattern.len().saturating_sub(1))
+                .find(|&j| pattern[j] == ':' && pattern[j + 1] == ']')
+        {
+            let class: String = pattern[i + 2..end].iter().collect();
+            matched |= class_matches(&class, c);
+            i = end + 2;
+            continue;
+        }
+
+        if start == '\\' {
+            i += 1;
+            start = *pattern.get(i)?;
+        }
+        i += 1;
+
+        if pattern.get(i) == Some(&'-') && pattern.get(i + 1).is_some_and(|&end| end != ']') {
+            let mut end = pattern[i + 1];
+            i += 2;
+            if end == '\\' {
+                end = *pattern.get(i)?;
+                i += 1;
+            }
+            matched |= start <= c && c <= end;
+        } else {
+            matched |= start == c;
+        }
+    }
+}
+
+fn match_chars(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            if rest.first() == Some(&'*') {
+                return match_chars(rest, text);
+            }
+            (0..=text.len()).any(|skip| match_chars(rest, &text[skip..]))
+        }
+        Some('?') => !text.is_empty() && match_chars(&pattern[1..], &text[1..]),
+        Some('[') => {
+            let Some(&c) = text.first() else {
+                return false;
+            };
+            match match_bracket(&pattern[1..], c) {
+                Some((matched, used)) => matched && match_chars(&pattern[1 + used..], &text[1..]),
+                None => c == '[' && match_chars(&pattern[1..], &text[1..]),
+            }
+        }
+        Some('\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && match_chars(&pattern[2..], &text[1..])
+        }
+        Some(&c) => text.first() == Some(&c) && match_chars(&pattern[1..], &text[1..]),
+    }
+}
+
+pub fn pattern_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_chars(&pattern, &text)
+}
+
+pub fn has_pattern_chars(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+pub fn unescape(pattern: &str) -> String {
+    let mut result = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            result.extend(chars.next());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn join(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else if prefix.ends_with('/') {
+        format!("{prefix}{name}")
+    } else {
+        format!("{prefix}/{name}")
+    }
+}
+
+fn read_names(prefix: &str) -> Vec<(String, bool)> {
+    let dir = if prefix.is_empty() { "." } else { prefix };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    entries
+        .flatten()
+        .map(|entry| {
+            let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+            (entry.file_name().to_string_lossy().to_string(), is_dir)
+        })
+        .collect()
+}
+
+// Every non-hidden path below `prefix`. Symlinked directories are not
+// followed, so a link cycle cannot send the walk round forever.
+fn walk(prefix: &str, dirs_only: bool, found: &mut Vec<String>) {
+    for (name, is_dir) in read_names(prefix) {
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = join(prefix, &name);
+        if is_dir {
+            found.push(path.clone());
+            walk(&path, dirs_only, found);
+        } else if !dirs_only {
+            found.push(path);
+        }
+    }
+}
+
+// Expands a pathname pattern to the sorted list of paths it matches. `**`
+// as a whole component matches any number of directories.
+pub fn expand_pathname(pattern: &str) -> Vec<String> {
+    let dirs_only = pattern.ends_with('/');
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    let mut paths = vec![if pattern.starts_with('/') {
+        "/".to_string()
+    } else {
+        String::new()
+    }];
+
+    for (index, component) in components.iter().enumerate() {
+        let last = index + 1 == components.len();
+        let mut next = Vec::new();
+
+        for prefix in &paths {
+            if *component == "**" {
+                if !last {
+                    next.push(prefix.clone());
+                }
+                walk(prefix, !last || dirs_only, &mut next);
+            } else if has_pattern_chars(component) {
+                let hidden = component.starts_with('.') || component.starts_with("\\.");
+                for (name, _) in read_names(prefix) {
+                    if name.starts_with('.') && !hidden || !pattern_matches(component, &name) {
+                        continue;
+                    }
+                    let path = join(prefix, &name);
+                    if last || Path::new(&path).is_dir() {
+                        next.push(path);
+                    }
+                }
+            } else {
+                let path = join(prefix, &unescape(component));
+                if Path::new(&path).symlink_metadata().is_ok() {
+                    next.push(path);
+                }
+            }
+        }
+
+        paths = next;
+    }
+
+    if dirs_only {
+        paths.retain(|path| Path::new(path).is_dir());
+        for path in &mut paths {
+            path.push('/');
+        }
+    }
+    if components.is_empty() {
+        paths.clear();
+    }
+
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(pattern_matches("*.rs", "main.rs"));
+        assert!(!pattern_matches("*.rs", "main.rc"));
+        assert!(pattern_matches("a?c", "abc"));
+        assert!(!pattern_matches("a?c", "ac"));
+        assert!(pattern_matches("*", ""));
+        assert!(pattern_matches("a*b*c", "aXbYbZc"));
+    }
+
+    #[test]
+    fn bracket_expressions() {
+        assert!(pattern_matches("[abc]x", "bx"));
+        assert!(!pattern_matches("[abc]x", "dx"));
+        assert!(pattern_matches("[a-z]", "q"));
+        assert!(pattern_matches("[!a-z]", "Q"));
+        assert!(pattern_matches("[^a-z]", "1"));
+        assert!(!pattern_matches("[!a-z]", "q"));
+    }
+
+    #[test]
+    fn escaped_characters_match_themselves() {
+        assert!(pattern_matches("\\*", "*"));
+        assert!(!pattern_matches("\\*", "a"));
+        assert!(pattern_matches("a\\?", "a?"));
+    }
+}
@@ -0,0 +1,694 @@
+use crate::lexer::{Token, tokenize};
+use colored::*;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// One command entered at the prompt. A line of the history file holds the
+// fields separated by tabs: start time in seconds since the epoch, duration
+// in milliseconds, exit status, session ID, working directory and command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub start: u64,
+    pub duration: Duration,
+    pub status: i32,
+    pub session: String,
+    pub cwd: PathBuf,
+    pub command: String,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+impl HistoryEntry {
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.start,
+            self.duration.as_millis(),
+            self.status,
+            self.session,
+            escape(&self.cwd.to_string_lossy()),
+            escape(&self.command)
+        )
+    }
+
+    fn parse(line: &str) -> Option<HistoryEntry> {
+        let mut fields = line.splitn(6, '\t');
+        Some(HistoryEntry {
+            start: fields.next()?.parse().ok()?,
+            duration: Duration::from_millis(fields.next()?.parse().ok()?),
+            status: fields.next()?.parse().ok()?,
+            session: fields.next()?.to_string(),
+            cwd: PathBuf::from(unescape(fields.next()?)),
+            command: unescape(fields.next()?),
+        })
+    }
+
+    // A line from the plain history files written before entries had any
+    // fields, which only knows the command
+    fn from_plain(line: &str) -> HistoryEntry {
+        HistoryEntry {
+            start: 0,
+            duration: Duration::ZERO,
+            status: 0,
+            session: String::new(),
+            cwd: PathBuf::new(),
+            command: unescape(line),
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn format_time(secs: u64) -> String {
+    let time = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if secs == 0 || unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return "-".repeat(19);
+    }
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    if millis < 1000 {
+        format!("{millis}ms")
+    } else if millis < 60_000 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else if millis < 3_600_000 {
+        format!("{}m{:02}s", millis / 60_000, millis / 1000 % 60)
+    } else {
+        format!("{}h{:02}m", millis / 3_600_000, millis / 60_000 % 60)
+    }
+}
+
+// The start of the period `--since` names: an age such as `30m`, `12h`,
+// `7d` or `2w`, or a local date written as YYYY-MM-DD
+fn parse_since(text: &str) -> Option<u64> {
+    if let Some((year, rest)) = text.split_once('-')
+        && let Some((month, day)) = rest.split_once('-')
+    {
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = year.parse::<i32>().ok()? - 1900;
+        tm.tm_mon = month.parse::<i32>().ok()? - 1;
+        tm.tm_mday = day.parse().ok()?;
+        tm.tm_isdst = -1;
+        let time = unsafe { libc::mktime(&mut tm) };
+        return u64::try_from(time).ok();
+    }
+
+    let split = text.len().checked_sub(1)?;
+    let (count, unit) = text.split_at(split);
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => return None,
+    };
+    Some(now().saturating_sub(count.parse::<u64>().ok()? * seconds))
+}
+
+#[derive(Default)]
+struct Filter {
+    failed: bool,
+    status: Option<i32>,
+    cwd: Option<PathBuf>,
+    since: Option<u64>,
+    session: Option<String>,
+    text: Option<String>,
+    count: Option<usize>,
+    verbose: bool,
+}
+
+impl Filter {
+    fn parse(args: &[&str], cwd: &Path, session: &str) -> Result<Filter, String> {
+        let mut filter = Filter::default();
+        let mut args = args.iter();
+
+        while let Some(&arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .copied()
+                    .ok_or_else(|| format!("{arg}: option requires an argument"))
+            };
+            match arg {
+                "--failed" => filter.failed = true,
+                "--status" => {
+                    let status = value()?;
+                    filter.status = Some(
+                        status
+                            .parse()
+                            .map_err(|_| format!("{status}: invalid status"))?,
+                    );
+                }
+                // Commands run in the directory or anywhere below it
+                "--cwd" => filter.cwd = Some(cwd.join(value()?)),
+                "--here" => filter.cwd = Some(cwd.to_path_buf()),
+                "--since" => {
+                    let since = value()?;
+                    filter.since =
+                        Some(parse_since(since).ok_or_else(|| format!("{since}: invalid time"))?);
+                }
+                "--session" => filter.session = Some(session.to_string()),
+                "--grep" => filter.text = Some(value()?.to_string()),
+                "-v" | "--verbose" => filter.verbose = true,
+                "-n" => {
+                    let count = value()?;
+                    filter.count = Some(
+                        count
+                            .parse()
+                            .map_err(|_| format!("{count}: invalid count"))?,
+                    );
+                }
+                _ => return Err(format!("{arg}: invalid option")),
+            }
+        }
+
+        Ok(filter)
+    }
+
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        (!self.failed || entry.status != 0)
+            && self.status.is_none_or(|status| entry.status == status)
+            && self
+                .cwd
+                .as_ref()
+                .is_none_or(|cwd| entry.cwd.starts_with(cwd))
+            && self.since.is_none_or(|since| entry.start >= since)
+            && self
+                .session
+                .as_ref()
+                .is_none_or(|session| &entry.session == session)
+            && self
+                .text
+                .as_ref()
+                .is_none_or(|text| entry.command.contains(text.as_str()))
+    }
+}
+
+// Every command entered at the prompt, in order. New entries are appended
+// to the file as they finish, so sessions running side by side all keep
+// theirs.
+#[derive(Default)]
+pub struct History {
+    path: Option<PathBuf>,
+    session: String,
+    entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    // Reads the entries in `path`, which later entries are appended to. A
+    // file that cannot be read leaves the history empty.
+    pub fn load(path: &Path) -> History {
+        let session = format!("{:x}{:x}", now(), std::process::id());
+        let mut history = History {
+            path: Some(path.to_path_buf()),
+            session,
+            entries: Vec::new(),
+        };
+
+        let content = fs::read_to_string(path).unwrap_or_default();
+        history.entries = content
+            .lines()
+            .filter(|line| !line.is_empty() && *line != "#V2")
+            .map(|line| HistoryEntry::parse(line).unwrap_or_else(|| HistoryEntry::from_plain(line)))
+            .collect();
+        history
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    pub fn record(
+        &mut self,
+        command: &str,
+        cwd: &Path,
+        start: u64,
+        duration: Duration,
+        status: i32,
+    ) -> io::Result<()> {
+        let entry = HistoryEntry {
+            start,
+            duration,
+            status,
+            session: self.session.clone(),
+            cwd: cwd.to_path_buf(),
+            command: command.to_string(),
+        };
+
+        let result = match &self.path {
+            Some(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                // One write on an append handle, so that sessions writing at
+                // the same time never split each other's lines
+                .and_then(|mut file| file.write_all(format!("{}\n", entry.to_line()).as_bytes())),
+            None => Ok(()),
+        };
+        self.entries.push(entry);
+        result
+    }
+
+    // The `history` builtin: numbered entries, optionally filtered by their
+    // fields
+    pub fn list(&self, args: &[&str], cwd: &Path) -> i32 {
+        let filter = match Filter::parse(args, cwd, &self.session) {
+            Ok(filter) => filter,
+            Err(e) => {
+                eprintln!("{}: {e}", "history".red().bold());
+                return 2;
+            }
+        };
+
+        let matching: Vec<(usize, &HistoryEntry)> = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| filter.matches(entry))
+            .collect();
+        let skip = filter
+            .count
+            .map_or(0, |count| matching.len().saturating_sub(count));
+
+        for (index, entry) in matching.into_iter().skip(skip) {
+            let status = if entry.status == 0 {
+                format!("{:>3}", entry.status).normal()
+            } else {
+                format!("{:>3}", entry.status).red()
+            };
+            print!(
+                "{:>5}  {}  {status} {:>7}  ",
+                index + 1,
+                format_time(entry.start),
+                format_duration(entry.duration)
+            );
+            if filter.verbose {
+                print!("{}  {}  ", entry.session, entry.cwd.display());
+            }
+            println!("{}", entry.command);
+        }
+        0
+    }
+}
+
+// A line after history expansion. A `:p` modifier asks for the line to be
+// shown and saved but not run.
+pub struct Expansion {
+    pub line: String,
+    pub execute: bool,
+}
+
+// The words of a command as history expansion counts them, with operators
+// as words of their own
+fn history_words(command: &str) -> Vec<String> {
+    match tokenize(command) {
+        Ok(tokens) => tokens
+            .iter()
+            .filter(|token| **token != Token::Newline)
+            .map(|token| token.to_string())
+            .collect(),
+        Err(_) => command.split_whitespace().map(str::to_string).collect(),
+    }
+}
+
+struct Expander<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    commands: Vec<&'a str>,
+    execute: bool,
+}
+
+impl<'a> Expander<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn read_while(&mut self, keep: impl Fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(&keep) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn last(&self) -> Result<&'a str, String> {
+        self.commands
+            .last()
+            .copied()
+            .ok_or_else(|| "!!: event not found".to_string())
+    }
+
+    // The command an event designator such as `!!`, `!42`, `!-2`, `!git`
+    // or `!?text?` refers to. `!$`, `!*` and `!:2` refer to the last one.
+    fn event(&mut self) -> Result<&'a str, String> {
+        let not_found = |text: &str| format!("!{text}: event not found");
+
+        match self.peek() {
+            Some('!') => {
+                self.pos += 1;
+                self.last()
+            }
+            Some('$' | '^' | '*' | ':') => self.last(),
+            Some(c) if c.is_ascii_digit() || c == '-' => {
+                let sign = if c == '-' {
+                    self.pos += 1;
+                    "-"
+                } else {
+                    ""
+                };
+                let digits = self.read_while(|c| c.is_ascii_digit());
+                let text = format!("{sign}{digits}");
+                let n: usize = digits.parse().map_err(|_| not_found(&text))?;
+                let index = if sign.is_empty() {
+                    n.checked_sub(1)
+                } else {
+                    self.commands.len().checked_sub(n)
+                };
+                index
+                    .and_then(|index| self.commands.get(index).copied())
+                    .ok_or_else(|| not_found(&text))
+            }
+            Some('?') => {
+                self.pos += 1;
+                let text = self.read_while(|c| c != '?');
+                self.pos += 1;
+                self.commands
+                    .iter()
+                    .rev()
+                    .find(|command| command.contains(&text))
+                    .copied()
+                    .ok_or_else(|| not_found(&format!("?{text}")))
+            }
+            _ => {
+                let prefix = self.read_while(|c| {
+                    !c.is_whitespace()
+                        && !matches!(
+                            c,
+                            ':' | ';' | '|' | '&' | '(' | ')' | '<' | '>' | '\'' | '"'
+                        )
+                });
+                self.commands
+                    .iter()
+                    .rev()
+                    .find(|command| command.starts_with(&prefix))
+                    .copied()
+                    .ok_or_else(|| not_found(&prefix))
+            }
+        }
+    }
+
+    fn word_index(&mut self, last: usize) -> Option<usize> {
+        match self.peek()? {
+            '^' => {
+                self.pos += 1;
+                Some(1)
+            }
+            '$' => {
+                self.pos += 1;
+                Some(last)
+            }
+            c if c.is_ascii_digit() => self.read_while(|c| c.is_ascii_digit()).parse().ok(),
+            _ => None,
+        }
+    }
+
+    // Selects words of `command` by a designator such as `$`, `^`, `*`,
+    // `2`, `1-3`, `2*` or `2-`, or keeps it whole without one
+    fn words(&mut self, command: &str) -> Result<String, String> {
+        let bad = || "bad word specifier".to_string();
+        let words = history_words(command);
+        let last = words.len().saturating_sub(1);
+
+        let designated = match (self.peek(), self.chars.get(self.pos + 1)) {
+            (Some('$' | '^' | '*'), _) => true,
+            (Some(':'), Some(c)) => c.is_ascii_digit() || matches!(c, '$' | '^' | '*' | '-'),
+            _ => false,
+        };
+        if !designated {
+            return Ok(command.to_string());
+        }
+        if self.peek() == Some(':') {
+            self.pos += 1;
+        }
+
+        let (first, end) = if self.peek() == Some('*') {
+            self.pos += 1;
+            (1, last)
+        } else {
+            let first = if self.peek() == Some('-') {
+                0
+            } else {
+                self.word_index(last).ok_or_else(bad)?
+            };
+            match self.peek() {
+                Some('*') => {
+                    self.pos += 1;
+                    (first, last)
+                }
+                Some('-') => {
+                    self.pos += 1;
+                    match self.word_index(last) {
+                        Some(end) => (first, end),
+                        // `2-` stops short of the last word
+                        None => (first, last.saturating_sub(1)),
+                    }
+                }
+                _ => (first, first),
+            }
+        };
+
+        if first > end {
+            // `!*` of a command with no arguments is nothing at all
+            return if first == 1 && end == 0 {
+                Ok(String::new())
+            } else {
+                Err(bad())
+            };
+        }
+        words
+            .get(first..=end)
+            .map(|words| words.join(" "))
+            .ok_or_else(bad)
+    }
+
+    // `:h` keeps the head of a path, `:t` its tail and `:r` drops the
+    // extension. `:p` prints the line without running it.
+    fn modifiers(&mut self, mut text: String) -> Result<String, String> {
+        while self.peek() == Some(':') {
+            let Some(modifier) = self.chars.get(self.pos + 1).copied() else {
+                break;
+            };
+            text = match modifier {
+                'h' => match text.rsplit_once('/') {
+                    Some(("", _)) => "/".to_string(),
+                    Some((head, _)) => head.to_string(),
+                    None => text,
+                },
+                't' => match text.rsplit_once('/') {
+                    Some((_, tail)) => tail.to_string(),
+                    None => text,
+                },
+                'r' => {
+                    let name_start = text.rfind('/').map_or(0, |slash| slash + 1);
+                    match text[name_start..].rfind('.') {
+                        Some(dot) if dot > 0 => text[..name_start + dot].to_string(),
+                        _ => text,
+                    }
+                }
+                'p' => {
+                    self.execute = false;
+                    text
+                }
+                _ => return Err(format!(":{modifier}: unrecognized history modifier")),
+            };
+            self.pos += 2;
+        }
+        Ok(text)
+    }
+
+    // `^old^new^` at the start of a line runs the last command with the
+    // first `old` replaced by `new`
+    fn quick_substitution(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let old = self.read_while(|c| c != '^');
+        self.pos += 1;
+        let new = self.read_while(|c| c != '^');
+        self.pos += 1;
+
+        let command = self.last()?;
+        if old.is_empty() || !command.contains(&old) {
+            return Err(format!("^{old}^{new}: substitution failed"));
+        }
+        Ok(command.replacen(&old, &new, 1))
+    }
+
+    fn expand(&mut self) -> Result<Option<String>, String> {
+        let mut line = String::new();
+        let mut expanded = false;
+        let mut single_quoted = false;
+        let mut double_quoted = false;
+
+        if self.peek() == Some('^') {
+            line = self.quick_substitution()?;
+            expanded = true;
+        }
+
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '\'' if !double_quoted => single_quoted = !single_quoted,
+                '"' if !single_quoted => double_quoted = !double_quoted,
+                '\\' if !single_quoted => {
+                    line.push(c);
+                    if let Some(next) = self.peek() {
+                        line.push(next);
+                        self.pos += 1;
+                    }
+                    continue;
+                }
+                // A `!` before a blank, `=` or `(`, or in `$!`, is itself
+                '!' if !single_quoted
+                    && !line.ends_with('$')
+                    && self.peek().is_some_and(|next| {
+                        !next.is_whitespace() && !matches!(next, '=' | '(' | '"')
+                    }) =>
+                {
+                    let command = self.event()?;
+                    let words = self.words(command)?;
+                    line.push_str(&self.modifiers(words)?);
+                    expanded = true;
+                    continue;
+                }
+                _ => {}
+            }
+            line.push(c);
+        }
+
+        Ok(expanded.then_some(line))
+    }
+}
+
+// Expands csh-style history references in a line entered at the prompt
+// against the commands in `entries`, numbered as `history` lists them.
+// Returns None when the line has no references.
+pub fn expand_history(line: &str, entries: &[HistoryEntry]) -> Result<Option<Expansion>, String> {
+    let mut expander = Expander {
+        chars: line.chars().collect(),
+        pos: 0,
+        commands: entries.iter().map(|entry| entry.command.as_str()).collect(),
+        execute: true,
+    };
+    let expanded = expander.expand()?;
+    Ok(expanded.map(|line| Expansion {
+        line,
+        execute: expander.execute,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(commands: &[&str]) -> Vec<HistoryEntry> {
+        commands
+            .iter()
+            .map(|command| HistoryEntry {
+                start: 0,
+                duration: Duration::ZERO,
+                status: 0,
+                session: String::new(),
+                cwd: PathBuf::from("/"),
+                command: command.to_string(),
+            })
+            .collect()
+    }
+
+    fn expand(line: &str) -> Result<Option<String>, String> {
+        let entries = entries(&["ls /tmp", "cp src/a.txt dest", "make test"]);
+        expand_history(line, &entries).map(|expansion| expansion.map(|e| e.line))
+    }
+
+    #[test]
+    fn event_designators() {
+        assert_eq!(expand("!!").unwrap().as_deref(), Some("make test"));
+        assert_eq!(expand("!1").unwrap().as_deref(), Some("ls /tmp"));
+        assert_eq!(expand("!-2").unwrap().as_deref(), Some("cp src/a.txt dest"));
+        assert_eq!(expand("!cp").unwrap().as_deref(), Some("cp src/a.txt dest"));
+        assert_eq!(expand("!?tmp?").unwrap().as_deref(), Some("ls /tmp"));
+        assert!(expand("!nothing").is_err());
+    }
+
+    #[test]
+    fn word_designators_and_modifiers() {
+        assert_eq!(expand("echo !$").unwrap().as_deref(), Some("echo test"));
+        assert_eq!(expand("echo !^").unwrap().as_deref(), Some("echo test"));
+        assert_eq!(expand("!-2:1").unwrap().as_deref(), Some("src/a.txt"));
+        assert_eq!(expand("!-2:*").unwrap().as_deref(), Some("src/a.txt dest"));
+        assert_eq!(expand("!-2:1:h").unwrap().as_deref(), Some("src"));
+        assert_eq!(expand("!-2:1:t").unwrap().as_deref(), Some("a.txt"));
+        assert_eq!(expand("!-2:1:r").unwrap().as_deref(), Some("src/a"));
+    }
+
+    #[test]
+    fn quick_substitution() {
+        assert_eq!(expand("^test^all").unwrap().as_deref(), Some("make all"));
+    }
+
+    #[test]
+    fn print_only() {
+        let entries = entries(&["ls"]);
+        let expansion = expand_history("!!:p", &entries).unwrap().unwrap();
+        assert_eq!(expansion.line, "ls");
+        assert!(!expansion.execute);
+    }
+
+    #[test]
+    fn lines_without_references_are_left_alone() {
+        assert_eq!(expand("echo hi").unwrap(), None);
+        assert_eq!(expand("echo '!!'").unwrap(), None);
+        assert_eq!(expand("echo a!= b").unwrap(), None);
+    }
+}
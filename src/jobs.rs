@@ -0,0 +1,788 @@
+use colored::*;
+use signal_hook::iterator::Signals;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Ignored by the interactive shell itself; children get the defaults back so
+// that the terminal can interrupt, quit or stop the foreground job.
+const INTERACTIVE_SIGNALS: [i32; 5] = [
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessStatus {
+    Running,
+    Stopped,
+    Exited(i32),
+}
+
+impl ProcessStatus {
+    fn from_wait_status(status: i32) -> ProcessStatus {
+        if libc::WIFSTOPPED(status) {
+            ProcessStatus::Stopped
+        } else if libc::WIFCONTINUED(status) {
+            ProcessStatus::Running
+        } else if libc::WIFEXITED(status) {
+            ProcessStatus::Exited(libc::WEXITSTATUS(status))
+        } else if libc::WIFSIGNALED(status) {
+            ProcessStatus::Exited(128 + libc::WTERMSIG(status))
+        } else {
+            ProcessStatus::Running
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subshell {
+    Background,
+    Substitution,
+    // A compound command in a pipeline, joining the given process group
+    Stage(i32),
+}
+
+pub struct Process {
+    pub pid: i32,
+    pub status: ProcessStatus,
+}
+
+pub struct Job {
+    pub id: usize,
+    pub pgid: i32,
+    pub command: String,
+    pub processes: Vec<Process>,
+    tmodes: Option<libc::termios>,
+    reported_stop: bool,
+}
+
+impl Job {
+    pub fn new(command: impl Into<String>) -> Job {
+        Job {
+            id: 0,
+            pgid: 0,
+            command: command.into(),
+            processes: Vec::new(),
+            tmodes: None,
+            reported_stop: false,
+        }
+    }
+
+    pub fn add_process(&mut self, pid: i32) {
+        if self.pgid == 0 {
+            self.pgid = pid;
+        }
+        self.processes.push(Process {
+            pid,
+            status: ProcessStatus::Running,
+        });
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.processes
+            .iter()
+            .all(|process| matches!(process.status, ProcessStatus::Exited(_)))
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        !self.is_completed() && !self.is_running()
+    }
+
+    fn is_running(&self) -> bool {
+        self.processes
+            .iter()
+            .any(|process| process.status == ProcessStatus::Running)
+    }
+
+    pub fn exit_status(&self) -> i32 {
+        match self.processes.last().map(|process| process.status) {
+            Some(ProcessStatus::Exited(code)) => code,
+            Some(ProcessStatus::Stopped) => 128 + libc::SIGTSTP,
+            _ => 0,
+        }
+    }
+
+    fn update(&mut self, pid: i32, wait_status: i32) -> bool {
+        match self.processes.iter_mut().find(|process| process.pid == pid) {
+            Some(process) => {
+                process.status = ProcessStatus::from_wait_status(wait_status);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn continued(&mut self) {
+        for process in &mut self.processes {
+            if process.status == ProcessStatus::Stopped {
+                process.status = ProcessStatus::Running;
+            }
+        }
+        self.reported_stop = false;
+    }
+
+    fn state_label(&self) -> String {
+        if self.is_completed() {
+            match self.exit_status() {
+                0 => "Done".to_string(),
+                code => format!("Exit {code}"),
+            }
+        } else if self.is_stopped() {
+            "Stopped".to_string()
+        } else {
+            "Running".to_string()
+        }
+    }
+}
+
+pub struct JobTable {
+    jobs: Vec<Job>,
+    // Job ids ordered by use; the last one is the current job (%+)
+    recent: Vec<usize>,
+    enabled: bool,
+    shell_pgid: i32,
+    terminal: Option<OwnedFd>,
+    shell_tmodes: Option<libc::termios>,
+    // kill(2) targets for every live job, shared with the SIGHUP handler
+    hangup_targets: Arc<Mutex<Vec<i32>>>,
+}
+
+impl Default for JobTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JobTable {
+    pub fn new() -> JobTable {
+        JobTable {
+            jobs: Vec::new(),
+            recent: Vec::new(),
+            enabled: false,
+            shell_pgid: unsafe { libc::getpgrp() },
+            terminal: None,
+            shell_tmodes: None,
+            hangup_targets: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn count(&self) -> usize {
+        self.jobs.len()
+    }
+
+    // Puts the shell in its own process group in the foreground of the
+    // terminal on stdin. Does nothing when stdin is not a terminal.
+    pub fn enable(&mut self) -> io::Result<()> {
+        if unsafe { libc::isatty(0) } == 0 {
+            return Ok(());
+        }
+
+        unsafe {
+            loop {
+                let pgrp = libc::getpgrp();
+                if libc::tcgetpgrp(0) == pgrp {
+                    break;
+                }
+                libc::kill(-pgrp, libc::SIGTTIN);
+            }
+
+            for signal in INTERACTIVE_SIGNALS {
+                libc::signal(signal, libc::SIG_IGN);
+            }
+
+            let pid = libc::getpid();
+            if libc::getpgrp() != pid {
+                // Fails harmlessly when the shell is already a session leader
+                libc::setpgid(pid, pid);
+            }
+            self.shell_pgid = libc::getpgrp();
+            libc::tcsetpgrp(0, self.shell_pgid);
+        }
+
+        let terminal = unsafe { libc::fcntl(0, libc::F_DUPFD_CLOEXEC, 10) };
+        if terminal < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let terminal = unsafe { OwnedFd::from_raw_fd(terminal) };
+        self.shell_tmodes = get_tmodes(terminal.as_raw_fd());
+        self.terminal = Some(terminal);
+        self.enabled = true;
+        Ok(())
+    }
+
+    fn terminal_fd(&self) -> RawFd {
+        self.terminal.as_ref().map_or(0, |fd| fd.as_raw_fd())
+    }
+
+    pub fn configure_command(&self, cmd: &mut Command, pgid: i32, foreground: bool) {
+        let enabled = self.enabled;
+        let terminal = self.terminal_fd();
+
+        unsafe {
+            cmd.pre_exec(move || {
+                if enabled {
+                    libc::setpgid(0, pgid);
+                    if foreground {
+                        libc::tcsetpgrp(terminal, libc::getpgrp());
+                    }
+                    for signal in INTERACTIVE_SIGNALS {
+                        libc::signal(signal, libc::SIG_DFL);
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    // Called from the parent as well as the child so that the process group
+    // exists whichever of the two runs first.
+    pub fn place_process(&self, pid: i32, job: &Job) {
+        if self.enabled {
+            unsafe { libc::setpgid(pid, job.pgid) };
+        }
+    }
+
+    // Prepares a forked child that runs shell code. A background job gets a
+    // process group of its own and a pipeline stage joins its job's group.
+    // A command substitution stays in the shell's group and keeps ignoring
+    // the terminal's stop signals like the shell does.
+    pub fn enter_subshell(&mut self, kind: Subshell) {
+        unsafe {
+            match kind {
+                Subshell::Background | Subshell::Stage(_) => {
+                    let pgid = match kind {
+                        Subshell::Stage(pgid) => pgid,
+                        _ => 0,
+                    };
+                    if self.enabled {
+                        libc::setpgid(0, pgid);
+                    } else if kind == Subshell::Background {
+                        let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDONLY);
+                        if null >= 0 {
+                            libc::dup2(null, 0);
+                            libc::close(null);
+                        }
+                    }
+                    for signal in INTERACTIVE_SIGNALS {
+                        libc::signal(signal, libc::SIG_DFL);
+                    }
+                }
+                Subshell::Substitution => {
+                    libc::signal(libc::SIGINT, libc::SIG_DFL);
+                    libc::signal(libc::SIGQUIT, libc::SIG_DFL);
+                }
+            }
+            // The handler thread of forward_hangup did not survive the fork
+            libc::signal(libc::SIGHUP, libc::SIG_DFL);
+        }
+
+        self.enabled = false;
+        self.jobs.clear();
+        self.recent.clear();
+        self.hangup_targets = Arc::new(Mutex::new(Vec::new()));
+    }
+
+    pub fn add(&mut self, mut job: Job) -> usize {
+        if job.id == 0 {
+            job.id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        }
+        let id = job.id;
+        let index = self
+            .jobs
+            .iter()
+            .position(|other| other.id > id)
+            .unwrap_or(self.jobs.len());
+        self.jobs.insert(index, job);
+        self.touch(id);
+        self.publish_targets(None);
+        id
+    }
+
+    fn touch(&mut self, id: usize) {
+        self.recent.retain(|&other| other != id);
+        self.recent.push(id);
+    }
+
+    fn remove(&mut self, index: usize) -> Job {
+        let job = self.jobs.remove(index);
+        self.recent.retain(|&other| other != job.id);
+        self.publish_targets(None);
+        job
+    }
+
+    fn publish_targets(&self, foreground: Option<&Job>) {
+        let targets = self
+            .jobs
+            .iter()
+            .chain(foreground)
+            .flat_map(|job| self.kill_targets(job))
+            .collect();
+        if let Ok(mut shared) = self.hangup_targets.lock() {
+            *shared = targets;
+        }
+    }
+
+    // A whole process group when job control made one, otherwise the
+    // individual processes of the job.
+    fn kill_targets(&self, job: &Job) -> Vec<i32> {
+        if self.enabled {
+            vec![-job.pgid]
+        } else {
+            job.processes
+                .iter()
+                .filter(|process| !matches!(process.status, ProcessStatus::Exited(_)))
+                .map(|process| process.pid)
+                .collect()
+        }
+    }
+
+    // Passes a SIGHUP sent to the shell on to every job before exiting.
+    pub fn forward_hangup(&self) -> io::Result<()> {
+        let targets = Arc::clone(&self.hangup_targets);
+        let mut signals = Signals::new([libc::SIGHUP])?;
+        thread::spawn(move || {
+            if signals.forever().next().is_some() {
+                if let Ok(targets) = targets.lock() {
+                    hangup(&targets);
+                }
+                std::process::exit(128 + libc::SIGHUP);
+            }
+        });
+        Ok(())
+    }
+
+    // Sends SIGHUP to the jobs that are still around when the shell exits.
+    pub fn hangup_all(&mut self) {
+        self.reap();
+        let targets: Vec<i32> = self
+            .jobs
+            .iter()
+            .filter(|job| !job.is_completed())
+            .flat_map(|job| self.kill_targets(job))
+            .collect();
+        hangup(&targets);
+    }
+
+    fn marker(&self, id: usize) -> char {
+        match self.recent.iter().rev().position(|&other| other == id) {
+            Some(0) => '+',
+            Some(1) => '-',
+            _ => ' ',
+        }
+    }
+
+    fn format_job(&self, job: &Job) -> String {
+        format!(
+            "[{}]{}  {:<24}{}",
+            job.id,
+            self.marker(job.id),
+            job.state_label(),
+            job.command
+        )
+    }
+
+    pub fn wait_foreground(&mut self, job: Job) -> i32 {
+        self.run_foreground(job, false)
+    }
+
+    fn run_foreground(&mut self, mut job: Job, resume: bool) -> i32 {
+        let terminal = self.terminal_fd();
+
+        if self.enabled {
+            unsafe { libc::tcsetpgrp(terminal, job.pgid) };
+        }
+        if resume {
+            if let Some(tmodes) = &job.tmodes {
+                unsafe { libc::tcsetattr(terminal, libc::TCSADRAIN, tmodes) };
+            }
+            unsafe { libc::kill(-job.pgid, libc::SIGCONT) };
+            job.continued();
+        }
+
+        self.publish_targets(Some(&job));
+        wait_for(&mut job, self.enabled);
+        self.publish_targets(None);
+
+        if self.enabled {
+            unsafe { libc::tcsetpgrp(terminal, self.shell_pgid) };
+            if job.is_stopped() {
+                job.tmodes = get_tmodes(terminal);
+            }
+            if let Some(tmodes) = &self.shell_tmodes {
+                unsafe { libc::tcsetattr(terminal, libc::TCSADRAIN, tmodes) };
+            }
+        }
+
+        let status = job.exit_status();
+        if self.enabled {
+            // The terminal echoed ^C or ^\ but left the cursor on that line
+            if status == 128 + libc::SIGINT {
+                eprintln!();
+            } else if status == 128 + libc::SIGQUIT {
+                eprintln!("Quit");
+            }
+        }
+        if job.is_stopped() {
+            job.reported_stop = true;
+            let id = self.add(job);
+            if let Some(job) = self.jobs.iter().find(|job| job.id == id) {
+                eprintln!();
+                eprintln!("{}", self.format_job(job));
+            }
+        }
+        status
+    }
+
+    fn reap(&mut self) {
+        for job in &mut self.jobs {
+            let pids: Vec<i32> = job
+                .processes
+                .iter()
+                .filter(|process| !matches!(process.status, ProcessStatus::Exited(_)))
+                .map(|process| process.pid)
+                .collect();
+
+            for pid in pids {
+                let mut status = 0;
+                let flags = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+                if unsafe { libc::waitpid(pid, &mut status, flags) } == pid {
+                    job.update(pid, status);
+                }
+            }
+        }
+    }
+
+    // Reports jobs that finished or stopped since the last prompt.
+    pub fn notify(&mut self) {
+        self.reap();
+
+        let mut index = 0;
+        while index < self.jobs.len() {
+            let job = &self.jobs[index];
+            if job.is_completed() {
+                eprintln!("{}", self.format_job(job));
+                self.remove(index);
+                continue;
+            }
+            if job.is_stopped() && !job.reported_stop {
+                eprintln!("{}", self.format_job(job));
+                self.jobs[index].reported_stop = true;
+            }
+            index += 1;
+        }
+    }
+
+    fn find(&self, spec: Option<&str>) -> Result<usize, String> {
+        let spec = spec.unwrap_or("%+");
+        let body = spec.strip_prefix('%').unwrap_or(spec);
+
+        let id = match body {
+            "" | "%" | "+" => self.recent.last().copied(),
+            "-" => self.recent.iter().rev().nth(1).copied(),
+            _ => {
+                if let Ok(id) = body.parse::<usize>() {
+                    Some(id)
+                } else if let Some(text) = body.strip_prefix('?') {
+                    self.jobs
+                        .iter()
+                        .find(|job| job.command.contains(text))
+                        .map(|job| job.id)
+                } else {
+                    self.jobs
+                        .iter()
+                        .find(|job| job.command.starts_with(body))
+                        .map(|job| job.id)
+                }
+            }
+        };
+
+        id.and_then(|id| self.jobs.iter().position(|job| job.id == id))
+            .ok_or_else(|| format!("{spec}: no such job"))
+    }
+
+    pub fn list(&mut self, args: &[&str]) -> i32 {
+        self.reap();
+        for line in self.listing(args) {
+            println!("{line}");
+        }
+        self.remove_completed();
+        0
+    }
+
+    // The lines `jobs` prints, one for each job
+    fn listing(&self, args: &[&str]) -> Vec<String> {
+        let show_pids = args.contains(&"-l");
+        let only_pids = args.contains(&"-p");
+
+        self.jobs
+            .iter()
+            .map(|job| {
+                if only_pids {
+                    job.pgid.to_string()
+                } else if show_pids {
+                    let pids: Vec<String> =
+                        job.processes.iter().map(|p| p.pid.to_string()).collect();
+                    format!(
+                        "[{}]{}  {} {:<24}{}",
+                        job.id,
+                        self.marker(job.id),
+                        pids.join(" "),
+                        job.state_label(),
+                        job.command
+                    )
+                } else {
+                    self.format_job(job)
+                }
+            })
+            .collect()
+    }
+
+    pub fn foreground(&mut self, args: &[&str]) -> i32 {
+        if !self.enabled {
+            eprintln!("{}: no job control", "fg".red().bold());
+            return 1;
+        }
+
+        let index = match self.find(args.first().copied()) {
+            Ok(index) => index,
+            Err(e) => {
+                eprintln!("{}: {e}", "fg".red().bold());
+                return 1;
+            }
+        };
+
+        let job = self.remove(index);
+        println!("{}", job.command);
+        let _ = io::stdout().flush();
+        self.run_foreground(job, true)
+    }
+
+    pub fn background(&mut self, args: &[&str]) -> i32 {
+        if !self.enabled {
+            eprintln!("{}: no job control", "bg".red().bold());
+            return 1;
+        }
+
+        let specs: Vec<Option<&str>> = if args.is_empty() {
+            vec![None]
+        } else {
+            args.iter().map(|arg| Some(*arg)).collect()
+        };
+
+        let mut status = 0;
+        for spec in specs {
+            let index = match self.find(spec) {
+                Ok(index) => index,
+                Err(e) => {
+                    eprintln!("{}: {e}", "bg".red().bold());
+                    status = 1;
+                    continue;
+                }
+            };
+
+            let job = &mut self.jobs[index];
+            if !job.is_stopped() {
+                eprintln!(
+                    "{}: job {} already in background",
+                    "bg".red().bold(),
+                    job.id
+                );
+                continue;
+            }
+
+            unsafe { libc::kill(-job.pgid, libc::SIGCONT) };
+            job.continued();
+            let id = job.id;
+            self.touch(id);
+            let job = &self.jobs[index];
+            println!("[{}]{} {} &", id, self.marker(id), job.command);
+        }
+        status
+    }
+
+    pub fn wait(&mut self, args: &[&str]) -> i32 {
+        if args.is_empty() {
+            for job in &mut self.jobs {
+                if !job.is_stopped() {
+                    wait_for(job, self.enabled);
+                }
+            }
+            self.remove_completed();
+            return 0;
+        }
+
+        let mut status = 0;
+        for arg in args {
+            let index = if arg.starts_with('%') {
+                self.find(Some(arg))
+            } else {
+                arg.parse::<i32>()
+                    .ok()
+                    .and_then(|pid| {
+                        self.jobs
+                            .iter()
+                            .position(|job| job.processes.iter().any(|p| p.pid == pid))
+                    })
+                    .ok_or_else(|| format!("pid {arg} is not a child of this shell"))
+            };
+
+            match index {
+                Ok(index) => {
+                    let job = &mut self.jobs[index];
+                    wait_for(job, self.enabled);
+                    status = job.exit_status();
+                }
+                Err(e) => {
+                    eprintln!("{}: {e}", "wait".red().bold());
+                    status = 127;
+                }
+            }
+        }
+
+        self.remove_completed();
+        status
+    }
+
+    fn remove_completed(&mut self) {
+        let mut index = 0;
+        while index < self.jobs.len() {
+            if self.jobs[index].is_completed() {
+                self.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    pub fn disown(&mut self, args: &[&str]) -> i32 {
+        if args.contains(&"-a") {
+            self.jobs.clear();
+            self.recent.clear();
+            self.publish_targets(None);
+            return 0;
+        }
+
+        let specs: Vec<Option<&str>> = if args.is_empty() {
+            vec![None]
+        } else {
+            args.iter().map(|arg| Some(*arg)).collect()
+        };
+
+        let mut status = 0;
+        for spec in specs {
+            match self.find(spec) {
+                Ok(index) => {
+                    self.remove(index);
+                }
+                Err(e) => {
+                    eprintln!("{}: {e}", "disown".red().bold());
+                    status = 1;
+                }
+            }
+        }
+        status
+    }
+}
+
+fn hangup(targets: &[i32]) {
+    for &target in targets {
+        if target != 0 && target != -1 {
+            unsafe {
+                libc::kill(target, libc::SIGHUP);
+                // Stopped jobs only see the hangup once they run again
+                libc::kill(target, libc::SIGCONT);
+            }
+        }
+    }
+}
+
+fn get_tmodes(fd: RawFd) -> Option<libc::termios> {
+    let mut tmodes: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut tmodes) } == 0 {
+        Some(tmodes)
+    } else {
+        None
+    }
+}
+
+fn wait_for(job: &mut Job, job_control: bool) {
+    while job.is_running() {
+        let target = if job_control {
+            -job.pgid
+        } else {
+            match job
+                .processes
+                .iter()
+                .find(|process| process.status == ProcessStatus::Running)
+            {
+                Some(process) => process.pid,
+                None => break,
+            }
+        };
+
+        let mut status = 0;
+        let pid = unsafe { libc::waitpid(target, &mut status, libc::WUNTRACED) };
+        if pid < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            for process in &mut job.processes {
+                if process.status == ProcessStatus::Running {
+                    process.status = ProcessStatus::Exited(1);
+                }
+            }
+            break;
+        }
+        job.update(pid, status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shell::Shell;
+
+    #[test]
+    fn wait_sets_the_status_of_a_background_job() {
+        let mut shell = Shell::new();
+        shell.run_line("sleep 0 & wait $!").unwrap();
+        assert_eq!(shell.last_status(), 0);
+        shell.run_line("sh -c 'exit 3' & wait $!").unwrap();
+        assert_eq!(shell.last_status(), 3);
+        shell.run_line("sh -c 'exit 4' & wait %1").unwrap();
+        assert_eq!(shell.last_status(), 4);
+        // Waited for jobs leave the table
+        assert_eq!(shell.state().jobs.count(), 0);
+        shell.run_line("wait 1").unwrap();
+        assert_eq!(shell.last_status(), 127);
+    }
+
+    #[test]
+    fn jobs_lists_background_jobs() {
+        let mut shell = Shell::new();
+        shell.run_line("sleep 5 &").unwrap();
+        let pid = shell.var("!").unwrap();
+        let jobs = &shell.state().jobs;
+        assert_eq!(jobs.count(), 1);
+        assert_eq!(
+            jobs.listing(&[]),
+            vec![format!("[1]+  {:<24}sleep 5", "Running")]
+        );
+        assert_eq!(jobs.listing(&["-p"]), vec![pid.clone()]);
+        assert_eq!(
+            jobs.listing(&["-l"]),
+            vec![format!("[1]+  {pid} {:<24}sleep 5", "Running")]
+        );
+        shell.run_line("kill $!; wait").unwrap();
+        assert_eq!(shell.state().jobs.count(), 0);
+    }
+}
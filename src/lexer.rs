@@ -0,0 +1,638 @@
+use crate::parser::ParseError;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Semicolon,
+    DoubleSemicolon,
+    Ampersand,
+    Pipe,
+    AndIf,
+    OrIf,
+    LeftParen,
+    RightParen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectOp {
+    Input,
+    Output,
+    Append,
+    DupInput,
+    DupOutput,
+    ReadWrite,
+    OutputBoth,
+    AppendBoth,
+    HereDoc,
+    HereDocStrip,
+    HereString,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Word(String),
+    Operator(Operator),
+    Redirect(Option<i32>, RedirectOp),
+    // The delimiter word of a here-document together with the lines that
+    // followed its command
+    HereDoc { delimiter: String, body: String },
+    // The expression of a `((...))` command
+    Arithmetic(String),
+    Newline,
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Operator::Semicolon => ";",
+            Operator::DoubleSemicolon => ";;",
+            Operator::Ampersand => "&",
+            Operator::Pipe => "|",
+            Operator::AndIf => "&&",
+            Operator::OrIf => "||",
+            Operator::LeftParen => "(",
+            Operator::RightParen => ")",
+        };
+        write!(f, "{s}")
+    }
+}
+
+impl fmt::Display for RedirectOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            RedirectOp::Input => "<",
+            RedirectOp::Output => ">",
+            RedirectOp::Append => ">>",
+            RedirectOp::DupInput => "<&",
+            RedirectOp::DupOutput => ">&",
+            RedirectOp::ReadWrite => "<>",
+            RedirectOp::OutputBoth => "&>",
+            RedirectOp::AppendBoth => "&>>",
+            RedirectOp::HereDoc => "<<",
+            RedirectOp::HereDocStrip => "<<-",
+            RedirectOp::HereString => "<<<",
+        };
+        write!(f, "{s}")
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{word}"),
+            Token::Operator(op) => write!(f, "{op}"),
+            Token::Redirect(Some(fd), op) => write!(f, "{fd}{op}"),
+            Token::Redirect(None, op) => write!(f, "{op}"),
+            Token::HereDoc { delimiter, .. } => write!(f, "{delimiter}"),
+            Token::Arithmetic(expression) => write!(f, "(({expression}))"),
+            Token::Newline => write!(f, "newline"),
+        }
+    }
+}
+
+// The delimiter of a here-document with its quotes removed, and whether
+// there were any. A quoted delimiter leaves the body unexpanded.
+pub fn here_doc_delimiter(word: &str) -> (String, bool) {
+    let delimiter: String = word
+        .chars()
+        .filter(|c| !matches!(c, '\'' | '"' | '\\'))
+        .collect();
+    let quoted = delimiter.len() != word.len();
+    (delimiter, quoted)
+}
+
+fn is_metachar(c: char) -> bool {
+    matches!(
+        c,
+        ' ' | '\t' | '\n' | ';' | '&' | '|' | '(' | ')' | '<' | '>'
+    )
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Lexer {
+    fn new(input: &str) -> Lexer {
+        Lexer {
+            chars: input.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next_if(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn tokenize(mut self) -> Result<Vec<Token>, ParseError> {
+        let mut tokens = Vec::new();
+        // Here-documents whose bodies start after the next newline, as the
+        // index of their delimiter token and whether to strip leading tabs
+        let mut here_docs: Vec<(usize, bool)> = Vec::new();
+
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\\' if self.chars.get(self.pos + 1) == Some(&'\n') => {
+                    self.pos += 2;
+                    // The command goes on in the next line
+                    if self.peek().is_none() {
+                        return Err(ParseError::UnexpectedEof);
+                    }
+                }
+                // A comment runs to the end of the line
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                '\n' => {
+                    self.pos += 1;
+                    tokens.push(Token::Newline);
+                    for (index, strip) in here_docs.drain(..) {
+                        if let Token::Word(delimiter) = &tokens[index] {
+                            let delimiter = delimiter.clone();
+                            let body = self.read_here_doc(&delimiter, strip)?;
+                            tokens[index] = Token::HereDoc { delimiter, body };
+                        }
+                    }
+                }
+                _ if matches!(tokens.last(), Some(Token::Word(word)) if word == "=~") => {
+                    tokens.push(Token::Word(self.read_regex()?));
+                }
+                '(' if self.chars.get(self.pos + 1) == Some(&'(') => {
+                    tokens.push(self.read_arithmetic()?);
+                }
+                c if is_metachar(c) => tokens.push(self.read_operator(None)),
+                _ => {
+                    let word = self.read_word()?;
+
+                    if let Some(Token::Redirect(
+                        _,
+                        op @ (RedirectOp::HereDoc | RedirectOp::HereDocStrip),
+                    )) = tokens.last()
+                    {
+                        here_docs.push((tokens.len(), *op == RedirectOp::HereDocStrip));
+                    }
+
+                    // A run of digits directly in front of < or > names a file descriptor
+                    if matches!(self.peek(), Some('<' | '>'))
+                        && word.chars().all(|c| c.is_ascii_digit())
+                        && let Ok(fd) = word.parse()
+                    {
+                        tokens.push(self.read_operator(Some(fd)));
+                    } else {
+                        tokens.push(Token::Word(word));
+                    }
+                }
+            }
+        }
+
+        match here_docs.first() {
+            Some(&(index, _)) => Err(ParseError::UnterminatedHereDoc(tokens[index].to_string())),
+            None => Ok(tokens),
+        }
+    }
+
+    // Reads lines up to the one that is just the delimiter, which may also
+    // be the last line of the input
+    fn read_here_doc(&mut self, delimiter: &str, strip: bool) -> Result<String, ParseError> {
+        let (delimiter, _) = here_doc_delimiter(delimiter);
+        let mut body = String::new();
+
+        while self.pos < self.chars.len() {
+            let end = self.chars[self.pos..]
+                .iter()
+                .position(|&c| c == '\n')
+                .map_or(self.chars.len(), |offset| self.pos + offset);
+            let mut line: String = self.chars[self.pos..end].iter().collect();
+            self.pos = (end + 1).min(self.chars.len());
+
+            if strip {
+                line = line.trim_start_matches('\t').to_string();
+            }
+            if line == delimiter {
+                return Ok(body);
+            }
+            body.push_str(&line);
+            body.push('\n');
+        }
+
+        Err(ParseError::UnterminatedHereDoc(delimiter))
+    }
+
+    fn read_arithmetic(&mut self) -> Result<Token, ParseError> {
+        self.pos += 2;
+        let mut expression = String::new();
+        let mut depth = 0;
+
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '(' => depth += 1,
+                ')' if depth == 0 => {
+                    return if self.next_if(')') {
+                        Ok(Token::Arithmetic(expression))
+                    } else {
+                        Err(ParseError::UnexpectedToken(")".to_string()))
+                    };
+                }
+                ')' => depth -= 1,
+                _ => {}
+            }
+            expression.push(c);
+        }
+
+        Err(ParseError::UnexpectedEof)
+    }
+
+    fn read_operator(&mut self, fd: Option<i32>) -> Token {
+        let c = self.chars[self.pos];
+        self.pos += 1;
+
+        match c {
+            ';' => {
+                if self.next_if(';') {
+                    Token::Operator(Operator::DoubleSemicolon)
+                } else {
+                    Token::Operator(Operator::Semicolon)
+                }
+            }
+            '&' => {
+                if self.next_if('&') {
+                    Token::Operator(Operator::AndIf)
+                } else if self.next_if('>') {
+                    if self.next_if('>') {
+                        Token::Redirect(None, RedirectOp::AppendBoth)
+                    } else {
+                        Token::Redirect(None, RedirectOp::OutputBoth)
+                    }
+                } else {
+                    Token::Operator(Operator::Ampersand)
+                }
+            }
+            '|' => {
+                if self.next_if('|') {
+                    Token::Operator(Operator::OrIf)
+                } else {
+                    Token::Operator(Operator::Pipe)
+                }
+            }
+            '(' => Token::Operator(Operator::LeftParen),
+            ')' => Token::Operator(Operator::RightParen),
+            '<' => {
+                if self.next_if('<') {
+                    if self.next_if('<') {
+                        Token::Redirect(fd, RedirectOp::HereString)
+                    } else if self.next_if('-') {
+                        Token::Redirect(fd, RedirectOp::HereDocStrip)
+                    } else {
+                        Token::Redirect(fd, RedirectOp::HereDoc)
+                    }
+                } else if self.next_if('&') {
+                    Token::Redirect(fd, RedirectOp::DupInput)
+                } else if self.next_if('>') {
+                    Token::Redirect(fd, RedirectOp::ReadWrite)
+                } else {
+                    Token::Redirect(fd, RedirectOp::Input)
+                }
+            }
+            _ => {
+                if self.next_if('>') {
+                    Token::Redirect(fd, RedirectOp::Append)
+                } else if self.next_if('&') {
+                    Token::Redirect(fd, RedirectOp::DupOutput)
+                } else {
+                    // >| behaves like > since noclobber is not supported
+                    self.next_if('|');
+                    Token::Redirect(fd, RedirectOp::Output)
+                }
+            }
+        }
+    }
+
+    fn starts_substitution(&self) -> bool {
+        self.peek() == Some('$') && self.chars.get(self.pos + 1) == Some(&'(')
+    }
+
+    fn starts_parameter(&self) -> bool {
+        self.peek() == Some('$') && self.chars.get(self.pos + 1) == Some(&'{')
+    }
+
+    fn read_word(&mut self) -> Result<String, ParseError> {
+        let mut word = String::new();
+
+        while let Some(c) = self.peek() {
+            if is_metachar(c) {
+                break;
+            }
+            self.read_word_part(c, &mut word)?;
+        }
+
+        Ok(word)
+    }
+
+    // The right side of `=~` in `[[ ]]`, where parentheses and a `|` inside
+    // them are part of the regex rather than operators
+    fn read_regex(&mut self) -> Result<String, ParseError> {
+        let mut word = String::new();
+        let mut depth = 0;
+
+        while let Some(c) = self.peek() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                '|' if depth > 0 => {}
+                c if is_metachar(c) => break,
+                _ => {
+                    self.read_word_part(c, &mut word)?;
+                    continue;
+                }
+            }
+            word.push(c);
+            self.pos += 1;
+        }
+
+        Ok(word)
+    }
+
+    fn read_word_part(&mut self, c: char, word: &mut String) -> Result<(), ParseError> {
+        if c == '\\' {
+            self.read_escape(word)
+        } else if c == '$' && self.chars.get(self.pos + 1) == Some(&'\'') {
+            self.read_ansi_quoted(word)
+        } else if c == '\'' || c == '"' {
+            self.read_quoted(c, word)
+        } else if c == '`' {
+            self.read_backquoted(word)
+        } else if self.starts_substitution() {
+            self.read_substitution(word)
+        } else if self.starts_parameter() {
+            self.read_parameter(word)
+        } else {
+            word.push(c);
+            self.pos += 1;
+            Ok(())
+        }
+    }
+
+    fn read_quoted(&mut self, quote: char, word: &mut String) -> Result<(), ParseError> {
+        word.push(quote);
+        self.pos += 1;
+
+        while let Some(c) = self.peek() {
+            if quote == '"' && c == '\\' {
+                self.read_escape(word)?;
+                continue;
+            }
+            if quote == '"' && c == '`' {
+                self.read_backquoted(word)?;
+                continue;
+            }
+            if quote == '"' && self.starts_substitution() {
+                self.read_substitution(word)?;
+                continue;
+            }
+            if quote == '"' && self.starts_parameter() {
+                self.read_parameter(word)?;
+                continue;
+            }
+
+            word.push(c);
+            self.pos += 1;
+            if c == quote {
+                return Ok(());
+            }
+        }
+
+        Err(ParseError::UnterminatedQuote(quote))
+    }
+
+    // Copies a `$(...)` into the word untouched. Quotes and nested
+    // substitutions are skipped over so that their parentheses do not count.
+    fn read_substitution(&mut self, word: &mut String) -> Result<(), ParseError> {
+        word.push_str("$(");
+        self.pos += 2;
+        let mut depth = 1;
+
+        while let Some(c) = self.peek() {
+            if c == '\\' {
+                self.read_escape(word)?;
+            } else if c == '\'' || c == '"' {
+                self.read_quoted(c, word)?;
+            } else if c == '`' {
+                self.read_backquoted(word)?;
+            } else if self.starts_substitution() {
+                self.read_substitution(word)?;
+            } else {
+                word.push(c);
+                self.pos += 1;
+                if c == '(' {
+                    depth += 1;
+                } else if c == ')' {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        Err(ParseError::UnterminatedSubstitution)
+    }
+
+    // Copies a `${...}` into the word untouched, so that the words of a
+    // default such as `${NAME:-two words}` stay in one token
+    fn read_parameter(&mut self, word: &mut String) -> Result<(), ParseError> {
+        word.push_str("${");
+        self.pos += 2;
+
+        while let Some(c) = self.peek() {
+            if c == '\\' {
+                self.read_escape(word)?;
+            } else if c == '\'' || c == '"' {
+                self.read_quoted(c, word)?;
+            } else if c == '`' {
+                self.read_backquoted(word)?;
+            } else if self.starts_substitution() {
+                self.read_substitution(word)?;
+            } else if self.starts_parameter() {
+                self.read_parameter(word)?;
+            } else {
+                word.push(c);
+                self.pos += 1;
+                if c == '}' {
+                    return Ok(());
+                }
+            }
+        }
+
+        Err(ParseError::UnterminatedParameter)
+    }
+
+    // Keeps a backslash and the character it escapes together for expansion
+    // to interpret. A backslash before a newline joins the two lines.
+    fn read_escape(&mut self, word: &mut String) -> Result<(), ParseError> {
+        match self.chars.get(self.pos + 1) {
+            Some('\n') if self.pos + 2 == self.chars.len() => {
+                return Err(ParseError::UnexpectedEof);
+            }
+            Some('\n') => {}
+            Some(&escaped) => {
+                word.push('\\');
+                word.push(escaped);
+            }
+            None => return Err(ParseError::UnexpectedEof),
+        }
+        self.pos += 2;
+        Ok(())
+    }
+
+    // Copies a `$'...'` string, in which a backslash can escape the quote
+    fn read_ansi_quoted(&mut self, word: &mut String) -> Result<(), ParseError> {
+        word.push_str("$'");
+        self.pos += 2;
+
+        while let Some(c) = self.peek() {
+            word.push(c);
+            self.pos += 1;
+            if c == '\\' {
+                if let Some(escaped) = self.peek() {
+                    word.push(escaped);
+                    self.pos += 1;
+                }
+            } else if c == '\'' {
+                return Ok(());
+            }
+        }
+
+        Err(ParseError::UnterminatedQuote('\''))
+    }
+
+    fn read_backquoted(&mut self, word: &mut String) -> Result<(), ParseError> {
+        word.push('`');
+        self.pos += 1;
+
+        while let Some(c) = self.peek() {
+            word.push(c);
+            self.pos += 1;
+            if c == '\\' {
+                if let Some(escaped) = self.peek() {
+                    word.push(escaped);
+                    self.pos += 1;
+                }
+            } else if c == '`' {
+                return Ok(());
+            }
+        }
+
+        Err(ParseError::UnterminatedQuote('`'))
+    }
+}
+
+pub fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    Lexer::new(input).tokenize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(text: &str) -> Token {
+        Token::Word(text.to_string())
+    }
+
+    #[test]
+    fn words_and_operators() {
+        assert_eq!(
+            tokenize("a b|c && d; e &").unwrap(),
+            [
+                word("a"),
+                word("b"),
+                Token::Operator(Operator::Pipe),
+                word("c"),
+                Token::Operator(Operator::AndIf),
+                word("d"),
+                Token::Operator(Operator::Semicolon),
+                word("e"),
+                Token::Operator(Operator::Ampersand),
+            ]
+        );
+    }
+
+    #[test]
+    fn quotes_keep_words_together() {
+        assert_eq!(
+            tokenize("echo 'a b' \"c $d\" e\\ f").unwrap(),
+            [word("echo"), word("'a b'"), word("\"c $d\""), word("e\\ f")]
+        );
+        assert_eq!(
+            tokenize("echo $(a b) ${c d}").unwrap(),
+            [word("echo"), word("$(a b)"), word("${c d}")]
+        );
+    }
+
+    #[test]
+    fn redirections() {
+        assert_eq!(
+            tokenize("a 2>&1 >>out <in").unwrap(),
+            [
+                word("a"),
+                Token::Redirect(Some(2), RedirectOp::DupOutput),
+                word("1"),
+                Token::Redirect(None, RedirectOp::Append),
+                word("out"),
+                Token::Redirect(None, RedirectOp::Input),
+                word("in"),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_are_dropped() {
+        assert_eq!(tokenize("a # b c").unwrap(), [word("a")]);
+        assert_eq!(tokenize("a#b").unwrap(), [word("a#b")]);
+    }
+
+    #[test]
+    fn unterminated_quotes_are_errors() {
+        assert!(tokenize("echo 'a").is_err());
+        assert!(tokenize("echo \"a").is_err());
+    }
+
+    #[test]
+    fn here_documents_take_the_following_lines() {
+        assert_eq!(
+            tokenize("cat <<EOF\nhello\nEOF\n").unwrap(),
+            [
+                word("cat"),
+                Token::Redirect(None, RedirectOp::HereDoc),
+                Token::HereDoc {
+                    delimiter: "EOF".to_string(),
+                    body: "hello\n".to_string(),
+                },
+                Token::Newline,
+            ]
+        );
+        assert_eq!(here_doc_delimiter("'EOF'"), ("EOF".to_string(), true));
+        assert_eq!(here_doc_delimiter("EOF"), ("EOF".to_string(), false));
+    }
+
+    #[test]
+    fn line_continuation_at_the_end_needs_more_input() {
+        assert_eq!(tokenize("a \\\n"), Err(ParseError::UnexpectedEof));
+        assert_eq!(tokenize("a\\\n"), Err(ParseError::UnexpectedEof));
+        assert_eq!(tokenize("a \\\nb").unwrap(), [word("a"), word("b")]);
+        assert_eq!(tokenize("a\\\nb").unwrap(), [word("ab")]);
+    }
+}
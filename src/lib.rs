@@ -1,3 +1,19 @@
+pub mod arith;
+pub mod ast;
+pub mod brace;
+pub mod builtins;
 pub mod commands;
 pub mod completion;
+pub mod condition;
+pub mod control;
+pub mod expand;
+pub mod git;
+pub mod glob;
+pub mod history;
+pub mod jobs;
+pub mod lexer;
 pub mod parser;
+pub mod prompt;
+pub mod redirect;
+pub mod shell;
+pub mod state;
@@ -1,63 +1,82 @@
-mod commands;
-mod completion;
-mod parser;
-
 use clap::{arg, command, value_parser};
 use colored::*;
-use commands::{execute_file_commands, execute_single_command, handle_builtin_command};
-use completion::{ShellHelper, create_editor};
-use parser::parse_arguments;
 use rustyline::Editor;
 use rustyline::error::ReadlineError;
-use signal_hook::{consts::SIGINT, iterator::Signals};
-use std::collections::HashMap;
-use std::env;
-use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::thread;
+use shell::commands::Flow;
+use shell::completion::{Edited, ShellHelper, create_editor};
+use shell::history::{self, History};
+use shell::parser::is_incomplete;
+use shell::prompt::{self, DEFAULT_PS1, DEFAULT_PS2};
+use shell::shell::Shell;
+use std::fs::File;
+use std::io::{self, IsTerminal, Read, Write};
+use std::iter;
+use std::mem::{self, ManuallyDrop};
+use std::os::fd::FromRawFd;
+use std::path::PathBuf;
+use std::process::{self, Stdio};
+use std::ptr;
+use std::time::Instant;
 
 fn handle_line(
     rl: &mut Editor<ShellHelper, rustyline::history::FileHistory>,
     readline: Result<String, ReadlineError>,
-    _history_file: &Path,
-    aliases: &mut HashMap<String, String>,
+    shell: &mut Shell,
 ) -> Result<bool, Box<dyn std::error::Error>> {
     match readline {
         Ok(line) => {
-            rl.add_history_entry(line.as_str())?;
+            // The structured history is the one source for expansion, `edit`
+            // and the line editor, which is only given what it records
+            shell.state_mut().last_line = shell
+                .state()
+                .history
+                .entries()
+                .last()
+                .map(|entry| entry.command.clone());
+            let (line, execute) =
+                match history::expand_history(&line, shell.state().history.entries()) {
+                    Ok(None) => (line, true),
+                    // The expanded line is shown and saved in place of what
+                    // was typed
+                    Ok(Some(expansion)) => {
+                        println!("{}", expansion.line);
+                        (expansion.line, expansion.execute)
+                    }
+                    Err(e) => {
+                        eprintln!("{}: {e}", "history".red().bold());
+                        return Ok(true);
+                    }
+                };
             let input = line.trim();
 
             if input.is_empty() {
                 return Ok(true);
             }
 
-            // Split by semicolons and execute each command
-            let commands: Vec<&str> = input.split(';').map(|cmd| cmd.trim()).collect();
-
-            for cmd_input in commands {
-                if cmd_input.is_empty() {
-                    continue;
-                }
-
-                let parts = parse_arguments(cmd_input);
-                if parts.is_empty() {
-                    continue;
-                }
-
-                let command = &parts[0];
-                let args: Vec<&str> = parts[1..].iter().map(|s| s.as_str()).collect();
+            let cwd = shell.cwd().to_path_buf();
+            let start = history::now();
+            let timer = Instant::now();
+            let result = if execute {
+                shell.run_line(input)
+            } else {
+                Ok(Flow::Status(shell.last_status()))
+            };
+            if let Err(e) = &result {
+                eprintln!("{}: {e}", "Error".red().bold());
+            }
 
-                if let Some(should_continue) = handle_builtin_command(command, &args, rl, aliases)?
-                {
-                    if !should_continue {
-                        return Ok(false);
-                    }
-                } else {
-                    execute_single_command(command, &args, aliases, true, cmd_input);
-                }
+            let status = shell.last_status();
+            let state = shell.state_mut();
+            state.last_duration = timer.elapsed();
+            if let Err(e) = state
+                .history
+                .record(input, &cwd, start, state.last_duration, status)
+            {
+                eprintln!("{}: Could not save history: {e}", "Error".red().bold());
             }
+            rl.add_history_entry(input)?;
 
-            Ok(true)
+            Ok(!matches!(result, Ok(Flow::Exit)))
         }
         Err(ReadlineError::Interrupted) => Ok(true),
         Err(ReadlineError::Eof) => Ok(false),
@@ -68,42 +87,184 @@ fn handle_line(
     }
 }
 
+fn execute_file_commands(file: &Option<PathBuf>, shell: &mut Shell) {
+    if let Some(file_path) = file {
+        if file_path.exists() {
+            if let Err(e) = shell.run_script(file_path) {
+                eprintln!("{}: {}: {e}", "Error".red().bold(), file_path.display());
+            }
+        } else {
+            eprintln!(
+                "{}: File not found: {}",
+                "Error".red().bold(),
+                file_path.display()
+            );
+        }
+    }
+}
+
+fn render_prompt(name: &str, default: &str, shell: &Shell) -> String {
+    let template = shell.var(name).unwrap_or_else(|| default.to_string());
+    prompt::render(&template, shell.state())
+}
+
+// The prompt printed by an external command given with --prompt
+fn run_prompt_hook(cmd: &str, shell: &Shell) -> io::Result<String> {
+    let output = shell
+        .state()
+        .command("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Keeps reading lines at the PS2 prompt while the input so far is an
+// unfinished command, such as an open quote or an `if` without its `fi`.
+// At end of input the unfinished command is run to report its error.
+fn read_continuation(
+    rl: &mut Editor<ShellHelper, rustyline::history::FileHistory>,
+    mut readline: Result<String, ReadlineError>,
+    shell: &Shell,
+) -> Result<String, ReadlineError> {
+    while let Ok(line) = &mut readline
+        && is_incomplete(line)
+    {
+        match rl.readline(&render_prompt("PS2", DEFAULT_PS2, shell)) {
+            Ok(more) => {
+                line.push('\n');
+                line.push_str(&more);
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    readline
+}
+
+// The line editor's SIGINT handler. Job control ignores SIGINT in the shell,
+// so the handler is only put back while a line is read at the prompt, where
+// it lets a newer git segment interrupt the line editor.
+struct EditorInterrupt(libc::sigaction);
+
+impl EditorInterrupt {
+    // Taken once the line editor is created and before job control starts.
+    // There is none when the line editor has no terminal to draw on.
+    fn capture() -> Option<EditorInterrupt> {
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        unsafe { libc::sigaction(libc::SIGINT, ptr::null(), &mut action) };
+        (action.sa_sigaction != libc::SIG_DFL && action.sa_sigaction != libc::SIG_IGN)
+            .then_some(EditorInterrupt(action))
+    }
+
+    fn readline(
+        &self,
+        rl: &mut Editor<ShellHelper, rustyline::history::FileHistory>,
+        prompt: &str,
+        initial: (&str, &str),
+    ) -> Result<String, ReadlineError> {
+        let mut ignored: libc::sigaction = unsafe { mem::zeroed() };
+        unsafe { libc::sigaction(libc::SIGINT, &self.0, &mut ignored) };
+        let readline = rl.readline_with_initial(prompt, initial);
+        unsafe { libc::sigaction(libc::SIGINT, &ignored, ptr::null_mut()) };
+        readline
+    }
+}
+
+// The row of the last character the line editor drew for a prompt and line,
+// counting from the row the prompt starts on
+fn last_row(text: &str, columns: usize) -> usize {
+    let (mut row, mut column) = (0, 0);
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                chars.by_ref().find(|c| c.is_ascii_alphabetic());
+            }
+            '\n' => {
+                row += 1;
+                column = 0;
+            }
+            _ => {
+                column += 1;
+                if column > columns {
+                    row += 1;
+                    column = 1;
+                }
+            }
+        }
+    }
+    row + (column == columns) as usize
+}
+
+// Clears an interrupted prompt and line, which end on the row above the
+// cursor, so that they can be drawn again in their place
+fn erase_prompt(prompt: &str, edited: &Edited, columns: usize) -> io::Result<()> {
+    let text = format!(
+        "{prompt}{}{}",
+        edited.line,
+        edited.hint.as_deref().unwrap_or_default()
+    );
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b[{}A\r\x1b[J", last_row(&text, columns) + 1)?;
+    stdout.flush()
+}
+
 fn read_and_execute(
     rl: &mut Editor<ShellHelper, rustyline::history::FileHistory>,
-    history_file: &Path,
     prompt: &Option<String>,
-    aliases: &mut HashMap<String, String>,
+    interrupt: &Option<EditorInterrupt>,
+    shell: &mut Shell,
 ) -> Result<bool, Box<dyn std::error::Error>> {
-    let current_dir = env::current_dir()?;
-    let home_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
-    let display_dir = if current_dir == home_dir {
-        "~".to_string()
-    } else if let Ok(stripped) = current_dir.strip_prefix(&home_dir) {
-        format!("~/{}/", stripped.display())
-    } else {
-        format!("{}/", current_dir.display())
-    };
-    let default_prompt = format!("{}{} ", display_dir.bright_blue().bold(), ">".bold());
-
-    let the_prompt = match &prompt {
-        Some(cmd) => {
-            let output = Command::new("sh")
-                .arg("-c")
-                .arg(cmd)
-                .stdout(Stdio::piped())
-                .stderr(Stdio::inherit())
-                .output()?;
-
-            match String::from_utf8(output.stdout) {
+    shell.state_mut().jobs.notify();
+
+    if let Some(helper) = rl.helper_mut() {
+        helper.set_functions(shell.state().functions.keys().cloned().collect());
+        helper.set_path(shell.var("PATH"));
+    }
+
+    // The line typed so far, either side of the cursor, when the prompt is
+    // drawn again for a newer git segment
+    let mut initial = (String::new(), String::new());
+    let readline = loop {
+        let the_prompt = match prompt {
+            Some(cmd) => match run_prompt_hook(cmd, shell) {
                 Ok(prompt_str) => prompt_str,
-                Err(_) => default_prompt,
-            }
+                Err(e) => {
+                    eprintln!("{}: {e}", "prompt".red().bold());
+                    render_prompt("PS1", DEFAULT_PS1, shell)
+                }
+            },
+            None => render_prompt("PS1", DEFAULT_PS1, shell),
+        };
+
+        let Some(interrupt) = interrupt else {
+            break rl.readline_with_initial(&the_prompt, (&initial.0, &initial.1));
+        };
+        if !shell.state().git.begin_edit() {
+            continue;
+        }
+        let readline = interrupt.readline(rl, &the_prompt, (&initial.0, &initial.1));
+        if shell.state().git.end_edit()
+            && matches!(readline, Err(ReadlineError::Interrupted))
+            && let Some(helper) = rl.helper()
+        {
+            let edited = helper.edited();
+            let columns = rl
+                .dimensions()
+                .map_or(80, |(columns, _)| usize::from(columns));
+            erase_prompt(&the_prompt, &edited, columns)?;
+            let (left, right) = edited.line.split_at(edited.pos);
+            initial = (left.to_string(), right.to_string());
+            continue;
         }
-        None => default_prompt,
+        break readline;
     };
-
-    let readline = rl.readline(&the_prompt);
-    handle_line(rl, readline, history_file, aliases)
+    let readline = read_continuation(rl, readline, shell);
+    shell.state().git.clear_shown();
+    handle_line(rl, readline, shell)
 }
 
 fn run_shell(
@@ -111,24 +272,84 @@ fn run_shell(
     prompt: Option<String>,
     file: Option<PathBuf>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut signals = Signals::new([SIGINT])?;
-    thread::spawn(move || for _sig in signals.forever() {});
-
     let mut rl = create_editor()?;
+    let interrupt = EditorInterrupt::capture();
+    let mut shell = Shell::new();
 
-    if rl.load_history(&history_file).is_err() {
+    let history = History::load(&history_file);
+    if history.entries().is_empty() {
         println!("{}: No previous history.", "Info".blue().bold());
     }
+    for entry in history.entries() {
+        rl.add_history_entry(entry.command.as_str())?;
+    }
+    shell.state_mut().history = history;
 
-    let mut aliases = HashMap::new();
-    execute_file_commands(&file, &mut aliases)?;
-    while read_and_execute(&mut rl, &history_file, &prompt, &mut aliases)? {}
+    shell.state_mut().interactive = true;
+    shell.enable_job_control()?;
+    execute_file_commands(&file, &mut shell);
+    while read_and_execute(&mut rl, &prompt, &interrupt, &mut shell)? {}
 
-    rl.save_history(&history_file)?;
+    shell.state_mut().jobs.hangup_all();
 
     Ok(())
 }
 
+// What a shell started without a terminal runs instead of the prompt
+enum Script {
+    Command(String),
+    File(PathBuf),
+    Stdin,
+}
+
+// Lines of stdin, read a byte at a time so that the input after a command
+// is left for the commands that read stdin themselves
+fn stdin_lines() -> impl Iterator<Item = io::Result<String>> {
+    // Wrapped so that dropping it does not close fd 0
+    let mut stdin = ManuallyDrop::new(unsafe { File::from_raw_fd(0) });
+    iter::from_fn(move || {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        loop {
+            match stdin.read(&mut byte) {
+                Ok(0) => break,
+                Ok(_) => {
+                    line.push(byte[0]);
+                    if byte[0] == b'\n' {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        (!line.is_empty()).then(|| Ok(String::from_utf8_lossy(&line).into_owned()))
+    })
+}
+
+// Runs a script to the end and returns the status the process exits with
+fn run_script(script: Script, name: String, args: Vec<String>) -> i32 {
+    let mut shell = Shell::new();
+    shell.set_args(&name, args);
+
+    let (source, result) = match script {
+        Script::Command(command) => ("-c".to_string(), shell.run_text(&command)),
+        Script::File(path) => match std::fs::read_to_string(&path) {
+            Ok(content) => (path.display().to_string(), shell.run_text(&content)),
+            Err(e) => {
+                eprintln!("{}: {}: {e}", "Error".red().bold(), path.display());
+                return 127;
+            }
+        },
+        Script::Stdin => ("stdin".to_string(), shell.run_lines(stdin_lines())),
+    };
+    if let Err(e) = result {
+        eprintln!("{}: {source}: {e}", "Error".red().bold());
+    }
+    let _ = io::stdout().flush();
+    shell.last_status()
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = command!()
         .arg(
@@ -147,13 +368,54 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         )
         .arg(
             arg!(
-                -f --file <FILE> "File to read commands from"
+                -f --file <FILE> "File to read commands from before the first prompt"
             )
             .required(false)
             .value_parser(value_parser!(PathBuf)),
         )
+        .arg(
+            arg!(
+                -c --command <STRING> "Commands to run instead of starting a prompt"
+            )
+            .required(false)
+            .value_parser(value_parser!(String)),
+        )
+        .arg(
+            arg!(
+                [ARGS] ... "Script to run and its arguments, or $0 and arguments with -c"
+            )
+            .trailing_var_arg(true)
+            .allow_hyphen_values(true),
+        )
         .get_matches();
 
+    let mut args: Vec<String> = matches
+        .get_many::<String>("ARGS")
+        .map(|args| args.cloned().collect())
+        .unwrap_or_default();
+    let program = std::env::args()
+        .next()
+        .unwrap_or_else(|| "shell".to_string());
+    let script = if let Some(command) = matches.get_one::<String>("command") {
+        Some(Script::Command(command.clone()))
+    } else if !args.is_empty() {
+        Some(Script::File(PathBuf::from(&args[0])))
+    } else if !io::stdin().is_terminal() {
+        Some(Script::Stdin)
+    } else {
+        None
+    };
+
+    if let Some(script) = script {
+        // The script's own name is $0 and the rest are its arguments
+        let name = match script {
+            Script::Stdin => program,
+            _ if args.is_empty() => program,
+            _ => args.remove(0),
+        };
+        process::exit(run_script(script, name, args));
+    }
+
     let history_file = matches
         .get_one::<PathBuf>("history")
         .cloned()
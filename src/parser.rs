@@ -1,117 +1,667 @@
-use std::env;
-
-pub fn expand_tilde(path: &str) -> String {
-    if path == "~" {
-        dirs::home_dir()
-            .map(|home| home.to_string_lossy().to_string())
-            .unwrap_or_else(|| path.to_string())
-    } else if path.starts_with("~/") {
-        dirs::home_dir()
-            .map(|home| home.join(&path[2..]).to_string_lossy().to_string())
-            .unwrap_or_else(|| path.to_string())
-    } else {
-        path.to_string()
+use crate::ast::{
+    AndOr, AndOrOp, Assignment, CaseArm, Command, CompoundCommand, CondExpr, Function, List,
+    Pipeline, Redirect, SimpleCommand, Word,
+};
+use crate::condition::{is_binary_operator, is_unary_operator};
+use crate::lexer::{Operator, RedirectOp, Token, tokenize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    UnterminatedQuote(char),
+    UnterminatedSubstitution,
+    UnterminatedParameter,
+    UnterminatedHereDoc(String),
+    UnexpectedToken(String),
+    UnexpectedEof,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnterminatedQuote(quote) => write!(f, "unterminated quote: {quote}"),
+            ParseError::UnterminatedSubstitution => {
+                write!(f, "unterminated command substitution")
+            }
+            ParseError::UnterminatedParameter => {
+                write!(f, "unterminated parameter expansion")
+            }
+            ParseError::UnterminatedHereDoc(delimiter) => {
+                write!(f, "here-document not terminated by `{delimiter}'")
+            }
+            ParseError::UnexpectedToken(token) => {
+                write!(f, "syntax error near unexpected token `{token}'")
+            }
+            ParseError::UnexpectedEof => write!(f, "syntax error: unexpected end of input"),
+        }
     }
 }
 
-pub fn expand_variables(input: &str) -> String {
-    let mut result = String::new();
-    let mut chars = input.chars().peekable();
-
-    while let Some(c) = chars.next() {
-        if c == '$' {
-            if let Some(&next_char) = chars.peek() {
-                if next_char == '{' {
-                    chars.next(); // consume '{'
-                    let mut var_name = String::new();
-                    let mut found_closing = false;
-
-                    for c in chars.by_ref() {
-                        if c == '}' {
-                            found_closing = true;
-                            break;
-                        }
-                        var_name.push(c);
-                    }
+impl std::error::Error for ParseError {}
 
-                    if found_closing {
-                        if let Ok(value) = env::var(&var_name) {
-                            result.push_str(&value);
-                        }
-                    } else {
-                        result.push_str("${");
-                        result.push_str(&var_name);
-                    }
-                } else if next_char.is_alphabetic() || next_char == '_' {
-                    let mut var_name = String::new();
-
-                    while let Some(&next_char) = chars.peek() {
-                        if next_char.is_alphanumeric() || next_char == '_' {
-                            var_name.push(chars.next().unwrap());
-                        } else {
-                            break;
-                        }
-                    }
+impl ParseError {
+    // Errors that more input could still fix
+    pub fn is_incomplete(&self) -> bool {
+        matches!(
+            self,
+            ParseError::UnterminatedQuote(_)
+                | ParseError::UnterminatedSubstitution
+                | ParseError::UnterminatedParameter
+                | ParseError::UnterminatedHereDoc(_)
+                | ParseError::UnexpectedEof
+        )
+    }
+}
+
+pub fn is_incomplete(input: &str) -> bool {
+    let mut parser = match Parser::new(input) {
+        Ok(parser) => parser,
+        Err(e) => return e.is_incomplete(),
+    };
+
+    loop {
+        match parser.next_command(&HashMap::new()) {
+            Ok(Some(_)) => {}
+            Ok(None) => return false,
+            Err(e) => return e.is_incomplete(),
+        }
+    }
+}
+
+const RESERVED_WORDS: [&str; 8] = ["then", "elif", "else", "fi", "do", "done", "esac", "}"];
+
+pub fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
-                    if let Ok(value) = env::var(&var_name) {
-                        result.push_str(&value);
+fn is_function_name(name: &str) -> bool {
+    !RESERVED_WORDS.contains(&name)
+        && !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':'))
+}
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    pub fn new(input: &str) -> Result<Parser, ParseError> {
+        Ok(Parser {
+            tokens: tokenize(input)?,
+            pos: 0,
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn unexpected(&self) -> ParseError {
+        match self.peek() {
+            Some(token) => ParseError::UnexpectedToken(token.to_string()),
+            None => ParseError::UnexpectedEof,
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while let Some(Token::Newline) = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    // Parses the next newline-terminated command. Aliases are looked up as
+    // each command is reached, so a definition affects the lines after it.
+    pub fn next_command(
+        &mut self,
+        aliases: &HashMap<String, String>,
+    ) -> Result<Option<List>, ParseError> {
+        self.skip_newlines();
+        if self.peek().is_none() {
+            return Ok(None);
+        }
+
+        let list = self.parse_list(aliases)?;
+        match self.peek() {
+            None | Some(Token::Newline) => Ok(Some(list)),
+            Some(_) => Err(self.unexpected()),
+        }
+    }
+
+    fn parse_list(&mut self, aliases: &HashMap<String, String>) -> Result<List, ParseError> {
+        let mut items = vec![self.parse_and_or(aliases)?];
+
+        loop {
+            match self.peek() {
+                Some(Token::Operator(Operator::Semicolon)) => {}
+                Some(Token::Operator(Operator::Ampersand)) => {
+                    if let Some(last) = items.last_mut() {
+                        last.background = true;
                     }
-                } else {
-                    result.push(c);
                 }
-            } else {
-                result.push(c);
+                _ => break,
             }
+
+            self.pos += 1;
+            if matches!(self.peek(), None | Some(Token::Newline)) {
+                break;
+            }
+            items.push(self.parse_and_or(aliases)?);
+        }
+
+        Ok(List { items })
+    }
+
+    fn parse_and_or(&mut self, aliases: &HashMap<String, String>) -> Result<AndOr, ParseError> {
+        let first = self.parse_pipeline(aliases)?;
+        let mut rest = Vec::new();
+
+        loop {
+            let op = match self.peek() {
+                Some(Token::Operator(Operator::AndIf)) => AndOrOp::And,
+                Some(Token::Operator(Operator::OrIf)) => AndOrOp::Or,
+                _ => break,
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            rest.push((op, self.parse_pipeline(aliases)?));
+        }
+
+        Ok(AndOr {
+            first,
+            rest,
+            background: false,
+        })
+    }
+
+    fn parse_pipeline(
+        &mut self,
+        aliases: &HashMap<String, String>,
+    ) -> Result<Pipeline, ParseError> {
+        let mut commands = vec![self.parse_command(aliases)?];
+
+        while let Some(Token::Operator(Operator::Pipe)) = self.peek() {
+            self.pos += 1;
+            self.skip_newlines();
+            commands.push(self.parse_command(aliases)?);
+        }
+
+        Ok(Pipeline { commands })
+    }
+
+    fn peek_word(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Word(word)) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn expect_word(&mut self, expected: &str) -> Result<(), ParseError> {
+        if self.peek_word() == Some(expected) {
+            self.pos += 1;
+            Ok(())
         } else {
-            result.push(c);
+            Err(self.unexpected())
         }
     }
 
-    result
-}
+    fn expect_operator(&mut self, expected: Operator) -> Result<(), ParseError> {
+        if self.peek() == Some(&Token::Operator(expected)) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
 
-pub fn parse_arguments(input: &str) -> Vec<String> {
-    let mut args = Vec::new();
-    let mut current_arg = String::new();
-    let mut in_quotes = false;
-    let mut quote_char = '"';
-    let mut chars = input.chars().peekable();
-
-    while let Some(c) = chars.next() {
-        match c {
-            '"' | '\'' if !in_quotes => {
-                in_quotes = true;
-                quote_char = c;
-            }
-            c if in_quotes && c == quote_char => {
-                in_quotes = false;
-            }
-            ' ' | '\t' if !in_quotes => {
-                if !current_arg.is_empty() {
-                    let expanded = expand_variables(&current_arg);
-                    args.push(expand_tilde(&expanded));
-                    current_arg.clear();
-                }
+    fn parse_command(&mut self, aliases: &HashMap<String, String>) -> Result<Command, ParseError> {
+        self.expand_alias(aliases)?;
+
+        if self.peek_word() == Some("function") {
+            self.pos += 1;
+            let name = match self.peek_word() {
+                Some(name) if is_function_name(name) => name.to_string(),
+                _ => return Err(self.unexpected()),
+            };
+            self.pos += 1;
+            if self.peek() == Some(&Token::Operator(Operator::LeftParen)) {
+                self.pos += 1;
+                self.expect_operator(Operator::RightParen)?;
+            }
+            return self.parse_function_body(aliases, name);
+        }
+
+        if let Some(name) = self.peek_word()
+            && is_function_name(name)
+            && self.tokens.get(self.pos + 1) == Some(&Token::Operator(Operator::LeftParen))
+        {
+            let name = name.to_string();
+            self.pos += 2;
+            self.expect_operator(Operator::RightParen)?;
+            return self.parse_function_body(aliases, name);
+        }
+
+        if let Some(Token::Arithmetic(expression)) = self.peek() {
+            let command = CompoundCommand::Arithmetic(Word::new(expression.clone()));
+            self.pos += 1;
+            return self.finish_compound(command);
+        }
+
+        let compound = match self.peek_word() {
+            Some("{") => self.parse_group(aliases)?,
+            Some("if") => self.parse_if(aliases)?,
+            Some("while") => self.parse_while(aliases, false)?,
+            Some("until") => self.parse_while(aliases, true)?,
+            Some("for") => self.parse_for(aliases)?,
+            Some("case") => self.parse_case(aliases)?,
+            Some("[[") => self.parse_conditional()?,
+            Some(word) if RESERVED_WORDS.contains(&word) => return Err(self.unexpected()),
+            _ => return Ok(Command::Simple(self.parse_simple_command()?)),
+        };
+        self.finish_compound(compound)
+    }
+
+    // Reads the redirections that follow a compound command
+    fn finish_compound(&mut self, compound: CompoundCommand) -> Result<Command, ParseError> {
+        let mut redirects = Vec::new();
+        while let Some(redirect) = self.parse_redirect()? {
+            redirects.push(redirect);
+        }
+        Ok(Command::Compound(compound, redirects))
+    }
+
+    fn parse_function_body(
+        &mut self,
+        aliases: &HashMap<String, String>,
+        name: String,
+    ) -> Result<Command, ParseError> {
+        self.skip_newlines();
+        if self.peek().is_none() {
+            return Err(ParseError::UnexpectedEof);
+        }
+        match self.parse_command(aliases)? {
+            Command::Compound(body, redirects) => Ok(Command::Function(Rc::new(Function {
+                name,
+                body,
+                redirects,
+            }))),
+            _ => Err(ParseError::UnexpectedToken(name)),
+        }
+    }
+
+    fn parse_group(
+        &mut self,
+        aliases: &HashMap<String, String>,
+    ) -> Result<CompoundCommand, ParseError> {
+        self.pos += 1;
+        let body = self.parse_compound_list(aliases, &["}"])?;
+        self.expect_word("}")?;
+        Ok(CompoundCommand::Group(body))
+    }
+
+    fn at_terminator(&self, terminators: &[&str]) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) => terminators.contains(&word.as_str()),
+            // `;;` ends the commands of a case arm
+            Some(Token::Operator(Operator::DoubleSemicolon)) => terminators.contains(&"esac"),
+            _ => false,
+        }
+    }
+
+    // Parses commands up to one of the reserved words in `terminators`, with
+    // newlines allowed anywhere a `;` is.
+    fn parse_compound_list(
+        &mut self,
+        aliases: &HashMap<String, String>,
+        terminators: &[&str],
+    ) -> Result<List, ParseError> {
+        let mut items = Vec::new();
 
-                // Skip multiple spaces
-                while let Some(&next_char) = chars.peek() {
-                    if next_char == ' ' || next_char == '\t' {
-                        chars.next();
-                    } else {
-                        break;
+        loop {
+            self.skip_newlines();
+            if self.at_terminator(terminators) {
+                break;
+            }
+
+            let mut and_or = self.parse_and_or(aliases)?;
+            match self.peek() {
+                Some(Token::Operator(Operator::Semicolon) | Token::Newline) => {}
+                Some(Token::Operator(Operator::Ampersand)) => and_or.background = true,
+                _ => {
+                    items.push(and_or);
+                    if !self.at_terminator(terminators) {
+                        return Err(self.unexpected());
                     }
+                    break;
+                }
+            }
+            self.pos += 1;
+            items.push(and_or);
+        }
+
+        if items.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(List { items })
+    }
+
+    fn parse_if(
+        &mut self,
+        aliases: &HashMap<String, String>,
+    ) -> Result<CompoundCommand, ParseError> {
+        self.pos += 1;
+        let mut branches = Vec::new();
+        let mut else_body = None;
+
+        loop {
+            let condition = self.parse_compound_list(aliases, &["then"])?;
+            self.expect_word("then")?;
+            let body = self.parse_compound_list(aliases, &["elif", "else", "fi"])?;
+            branches.push((condition, body));
+
+            match self.peek_word() {
+                Some("elif") => self.pos += 1,
+                Some("else") => {
+                    self.pos += 1;
+                    else_body = Some(self.parse_compound_list(aliases, &["fi"])?);
+                    self.expect_word("fi")?;
+                    break;
+                }
+                _ => {
+                    self.expect_word("fi")?;
+                    break;
+                }
+            }
+        }
+
+        Ok(CompoundCommand::If {
+            branches,
+            else_body,
+        })
+    }
+
+    fn parse_do_group(&mut self, aliases: &HashMap<String, String>) -> Result<List, ParseError> {
+        self.skip_newlines();
+        self.expect_word("do")?;
+        let body = self.parse_compound_list(aliases, &["done"])?;
+        self.expect_word("done")?;
+        Ok(body)
+    }
+
+    fn parse_while(
+        &mut self,
+        aliases: &HashMap<String, String>,
+        until: bool,
+    ) -> Result<CompoundCommand, ParseError> {
+        self.pos += 1;
+        let condition = self.parse_compound_list(aliases, &["do"])?;
+        let body = self.parse_do_group(aliases)?;
+        Ok(CompoundCommand::While {
+            condition,
+            body,
+            until,
+        })
+    }
+
+    fn parse_for(
+        &mut self,
+        aliases: &HashMap<String, String>,
+    ) -> Result<CompoundCommand, ParseError> {
+        self.pos += 1;
+        let variable = match self.peek_word() {
+            Some(name) if is_name(name) => name.to_string(),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+
+        self.skip_newlines();
+        let mut words = None;
+        if self.peek_word() == Some("in") {
+            self.pos += 1;
+            let mut list = Vec::new();
+            while let Some(Token::Word(word)) = self.peek() {
+                list.push(Word::new(word.clone()));
+                self.pos += 1;
+            }
+            words = Some(list);
+        }
+        if matches!(
+            self.peek(),
+            Some(Token::Operator(Operator::Semicolon) | Token::Newline)
+        ) {
+            self.pos += 1;
+        }
+
+        let body = self.parse_do_group(aliases)?;
+        Ok(CompoundCommand::For {
+            variable,
+            words,
+            body,
+        })
+    }
+
+    fn parse_case(
+        &mut self,
+        aliases: &HashMap<String, String>,
+    ) -> Result<CompoundCommand, ParseError> {
+        self.pos += 1;
+        let word = match self.peek() {
+            Some(Token::Word(word)) => Word::new(word.clone()),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        self.skip_newlines();
+        self.expect_word("in")?;
+
+        let mut arms = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.peek_word() == Some("esac") {
+                self.pos += 1;
+                break;
+            }
+
+            if self.peek() == Some(&Token::Operator(Operator::LeftParen)) {
+                self.pos += 1;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                match self.peek() {
+                    Some(Token::Word(pattern)) => patterns.push(Word::new(pattern.clone())),
+                    _ => return Err(self.unexpected()),
+                }
+                self.pos += 1;
+                if self.peek() != Some(&Token::Operator(Operator::Pipe)) {
+                    break;
                 }
+                self.pos += 1;
             }
-            _ => {
-                current_arg.push(c);
+            self.expect_operator(Operator::RightParen)?;
+
+            // An arm may be empty: `pattern) ;;`
+            self.skip_newlines();
+            let body = match self.peek() {
+                Some(Token::Operator(Operator::DoubleSemicolon)) => List { items: Vec::new() },
+                Some(Token::Word(word)) if word == "esac" => List { items: Vec::new() },
+                _ => self.parse_compound_list(aliases, &["esac"])?,
+            };
+            arms.push(CaseArm { patterns, body });
+
+            if self.peek() == Some(&Token::Operator(Operator::DoubleSemicolon)) {
+                self.pos += 1;
+            } else if self.peek_word() != Some("esac") {
+                return Err(self.unexpected());
             }
         }
+
+        Ok(CompoundCommand::Case { word, arms })
     }
 
-    if !current_arg.is_empty() {
-        let expanded = expand_variables(&current_arg);
-        args.push(expand_tilde(&expanded));
+    fn parse_conditional(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.pos += 1;
+        let expression = self.parse_cond_or()?;
+        self.expect_word("]]")?;
+        Ok(CompoundCommand::Conditional(expression))
     }
 
-    args
+    fn parse_cond_or(&mut self) -> Result<CondExpr, ParseError> {
+        let mut left = self.parse_cond_and()?;
+        while self.peek() == Some(&Token::Operator(Operator::OrIf)) {
+            self.pos += 1;
+            self.skip_newlines();
+            left = CondExpr::Or(Box::new(left), Box::new(self.parse_cond_and()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_cond_and(&mut self) -> Result<CondExpr, ParseError> {
+        let mut left = self.parse_cond_not()?;
+        while self.peek() == Some(&Token::Operator(Operator::AndIf)) {
+            self.pos += 1;
+            self.skip_newlines();
+            left = CondExpr::And(Box::new(left), Box::new(self.parse_cond_not()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_cond_not(&mut self) -> Result<CondExpr, ParseError> {
+        if self.peek_word() == Some("!") {
+            self.pos += 1;
+            return Ok(CondExpr::Not(Box::new(self.parse_cond_not()?)));
+        }
+        self.parse_cond_primary()
+    }
+
+    // A word inside `[[ ]]`, where `<` and `>` compare strings instead of
+    // redirecting
+    fn cond_word(&self, offset: usize) -> Option<&str> {
+        match self.tokens.get(self.pos + offset)? {
+            Token::Word(word) if word != "]]" => Some(word),
+            Token::Redirect(None, RedirectOp::Input) => Some("<"),
+            Token::Redirect(None, RedirectOp::Output) => Some(">"),
+            _ => None,
+        }
+    }
+
+    fn parse_cond_primary(&mut self) -> Result<CondExpr, ParseError> {
+        if self.peek() == Some(&Token::Operator(Operator::LeftParen)) {
+            self.pos += 1;
+            let expression = self.parse_cond_or()?;
+            self.expect_operator(Operator::RightParen)?;
+            return Ok(expression);
+        }
+
+        let Some(first) = self.cond_word(0).map(Word::new) else {
+            return Err(self.unexpected());
+        };
+        if let Some(op) = self.cond_word(1).filter(|op| is_binary_operator(op)) {
+            let op = op.to_string();
+            let Some(right) = self.cond_word(2).map(Word::new) else {
+                self.pos += 2;
+                return Err(self.unexpected());
+            };
+            self.pos += 3;
+            return Ok(CondExpr::Binary(first, op, right));
+        }
+        if is_unary_operator(&first.raw)
+            && let Some(operand) = self.cond_word(1).map(Word::new)
+        {
+            self.pos += 2;
+            return Ok(CondExpr::Unary(first.raw, operand));
+        }
+        self.pos += 1;
+        Ok(CondExpr::Word(first))
+    }
+
+    fn parse_redirect(&mut self) -> Result<Option<Redirect>, ParseError> {
+        let Some(&Token::Redirect(fd, op)) = self.peek() else {
+            return Ok(None);
+        };
+        self.pos += 1;
+
+        match self.peek() {
+            Some(Token::Word(target)) => {
+                let target = Word::new(target.clone());
+                self.pos += 1;
+                Ok(Some(Redirect {
+                    fd,
+                    op,
+                    target,
+                    body: None,
+                }))
+            }
+            Some(Token::HereDoc { delimiter, body }) => {
+                let redirect = Redirect {
+                    fd,
+                    op,
+                    target: Word::new(delimiter.clone()),
+                    body: Some(body.clone()),
+                };
+                self.pos += 1;
+                Ok(Some(redirect))
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn parse_simple_command(&mut self) -> Result<SimpleCommand, ParseError> {
+        let mut assignments = Vec::new();
+        let mut words = Vec::new();
+        let mut redirects = Vec::new();
+
+        loop {
+            if let Some(redirect) = self.parse_redirect()? {
+                redirects.push(redirect);
+            } else if let Some(Token::Word(word)) = self.peek() {
+                match word.split_once('=') {
+                    Some((name, value)) if words.is_empty() && is_name(name) => {
+                        assignments.push(Assignment {
+                            name: name.to_string(),
+                            value: Word::new(value),
+                        });
+                    }
+                    _ => words.push(Word::new(word.clone())),
+                }
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+
+        if assignments.is_empty() && words.is_empty() && redirects.is_empty() {
+            return Err(self.unexpected());
+        }
+
+        Ok(SimpleCommand {
+            assignments,
+            words,
+            redirects,
+        })
+    }
+
+    fn expand_alias(&mut self, aliases: &HashMap<String, String>) -> Result<(), ParseError> {
+        let mut expanded = HashSet::new();
+
+        while let Some(Token::Word(name)) = self.peek() {
+            let Some(value) = aliases.get(name) else {
+                break;
+            };
+            if !expanded.insert(name.clone()) {
+                break;
+            }
+
+            let replacement = tokenize(value)?;
+            self.tokens.splice(self.pos..self.pos + 1, replacement);
+        }
+
+        Ok(())
+    }
 }
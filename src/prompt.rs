@@ -0,0 +1,278 @@
+use crate::history::format_duration;
+use crate::state::ShellState;
+use colored::control::SHOULD_COLORIZE;
+use std::ffi::CStr;
+use std::path::PathBuf;
+
+// The prompts used when PS1 and PS2 are not set
+pub const DEFAULT_PS1: &str = "\\{bold}\\{bright_blue}\\w/\\{reset}\\{bold}>\\{reset} ";
+pub const DEFAULT_PS2: &str = "> ";
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+// Select Graphic Rendition codes for `\{name}`
+fn color_code(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "reset" => "0",
+        "bold" => "1",
+        "dim" => "2",
+        "italic" => "3",
+        "underline" => "4",
+        "black" => "30",
+        "red" => "31",
+        "green" => "32",
+        "yellow" => "33",
+        "blue" => "34",
+        "magenta" => "35",
+        "cyan" => "36",
+        "white" => "37",
+        "bright_black" => "90",
+        "bright_red" => "91",
+        "bright_green" => "92",
+        "bright_yellow" => "93",
+        "bright_blue" => "94",
+        "bright_magenta" => "95",
+        "bright_cyan" => "96",
+        "bright_white" => "97",
+        _ => return None,
+    })
+}
+
+fn local_time() -> libc::tm {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        libc::localtime_r(&now, &mut tm);
+    }
+    tm
+}
+
+fn host_name() -> String {
+    let mut buffer = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr(), buffer.len() - 1) } != 0 {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(buffer.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn user_name(state: &ShellState) -> String {
+    if let Some(user) = state.lookup("USER") {
+        return user;
+    }
+    let uid = unsafe { libc::geteuid() };
+    let passwd = unsafe { libc::getpwuid(uid) };
+    if passwd.is_null() {
+        return uid.to_string();
+    }
+    unsafe { CStr::from_ptr((*passwd).pw_name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+// The working directory with the home directory shortened to `~`
+fn display_dir(state: &ShellState) -> String {
+    let home = state
+        .lookup("HOME")
+        .map(PathBuf::from)
+        .or_else(dirs::home_dir);
+    if let Some(home) = home
+        && let Ok(rest) = state.cwd.strip_prefix(&home)
+        && home.parent().is_some()
+    {
+        if rest.as_os_str().is_empty() {
+            return "~".to_string();
+        }
+        return format!("~/{}", rest.display());
+    }
+    state.cwd.display().to_string()
+}
+
+fn twelve_hour(hour: i32) -> i32 {
+    match hour % 12 {
+        0 => 12,
+        hour => hour,
+    }
+}
+
+// Expands the escapes of a PS1 or PS2 template:
+//
+//   \w  working directory    \W  its last component   \u  user
+//   \h  host up to the dot   \H  full host            \s  shell name
+//   \t  24-hour HH:MM:SS     \T  12-hour HH:MM:SS     \A  24-hour HH:MM
+//   \@  12-hour am/pm time   \d  date as "Tue May 26"
+//   \?  last exit status     \D  last command's run time
+//   \j  number of jobs       \!  history number of the next command
+//   \g  git branch and status, empty outside a repository
+//   \$  `#` for root, `$` otherwise
+//   \n  newline   \e  escape   \a  bell   \\  backslash
+//   \{name}  a color or style such as red, bright_blue, bold or reset
+//
+// `\[` and `\]`, which other shells use around escape sequences, are
+// accepted and dropped since the line editor measures the prompt itself.
+pub fn render(template: &str, state: &ShellState) -> String {
+    let mut prompt = String::new();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            prompt.push(c);
+            continue;
+        }
+        let Some(escape) = chars.next() else {
+            prompt.push('\\');
+            break;
+        };
+        match escape {
+            'w' => prompt.push_str(&display_dir(state)),
+            'W' => {
+                let dir = display_dir(state);
+                match dir.rsplit_once('/') {
+                    Some((_, "")) | None => prompt.push_str(&dir),
+                    Some((_, name)) => prompt.push_str(name),
+                }
+            }
+            'u' => prompt.push_str(&user_name(state)),
+            'h' => prompt.push_str(host_name().split('.').next().unwrap_or_default()),
+            'H' => prompt.push_str(&host_name()),
+            's' => {
+                let name = &state.script_name;
+                prompt.push_str(name.rsplit('/').next().unwrap_or(name));
+            }
+            't' | 'T' | 'A' | '@' | 'd' => {
+                let tm = local_time();
+                let text = match escape {
+                    't' => format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec),
+                    'T' => format!(
+                        "{:02}:{:02}:{:02}",
+                        twelve_hour(tm.tm_hour),
+                        tm.tm_min,
+                        tm.tm_sec
+                    ),
+                    'A' => format!("{:02}:{:02}", tm.tm_hour, tm.tm_min),
+                    '@' => format!(
+                        "{:02}:{:02} {}",
+                        twelve_hour(tm.tm_hour),
+                        tm.tm_min,
+                        if tm.tm_hour < 12 { "AM" } else { "PM" }
+                    ),
+                    _ => format!(
+                        "{} {} {:02}",
+                        DAYS[tm.tm_wday.clamp(0, 6) as usize],
+                        MONTHS[tm.tm_mon.clamp(0, 11) as usize],
+                        tm.tm_mday
+                    ),
+                };
+                prompt.push_str(&text);
+            }
+            '?' => prompt.push_str(&state.last_status.to_string()),
+            'D' => prompt.push_str(&format_duration(state.last_duration)),
+            'g' => prompt.push_str(&state.git.segment(state)),
+            'j' => prompt.push_str(&state.jobs.count().to_string()),
+            '!' => prompt.push_str(&(state.history.entries().len() + 1).to_string()),
+            '$' => prompt.push(if unsafe { libc::geteuid() } == 0 {
+                '#'
+            } else {
+                '$'
+            }),
+            'n' => prompt.push('\n'),
+            'e' => prompt.push('\x1b'),
+            'a' => prompt.push('\x07'),
+            '\\' => prompt.push('\\'),
+            '[' | ']' => {}
+            '{' => {
+                let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                match color_code(&name) {
+                    Some(code) if SHOULD_COLORIZE.should_colorize() => {
+                        prompt.push_str(&format!("\x1b[{code}m"));
+                    }
+                    Some(_) => {}
+                    None => prompt.push_str(&format!("\\{{{name}}}")),
+                }
+            }
+            _ => {
+                prompt.push('\\');
+                prompt.push(escape);
+            }
+        }
+    }
+
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use colored::control;
+
+    fn state() -> ShellState {
+        let mut state = ShellState::new();
+        state.set_var("USER", "alice").unwrap();
+        state.set_var("HOME", "/home/alice").unwrap();
+        state.cwd = PathBuf::from("/home/alice/src/shell");
+        state.last_status = 3;
+        state
+    }
+
+    #[test]
+    fn user_host_and_directory() {
+        let state = state();
+        let host = host_name();
+        let short_host = host.split('.').next().unwrap_or_default();
+        assert_eq!(
+            render("\\u@\\h:\\w", &state),
+            format!("alice@{short_host}:~/src/shell")
+        );
+        assert_eq!(render("\\H \\W", &state), format!("{host} shell"));
+        assert_eq!(render("[\\?]", &state), "[3]");
+    }
+
+    #[test]
+    fn directories_outside_home_are_shown_whole() {
+        let mut state = state();
+        state.cwd = PathBuf::from("/home/alice");
+        assert_eq!(render("\\w \\W", &state), "~ ~");
+        state.cwd = PathBuf::from("/tmp/build");
+        assert_eq!(render("\\w \\W", &state), "/tmp/build build");
+        state.cwd = PathBuf::from("/home/alicia");
+        assert_eq!(render("\\w", &state), "/home/alicia");
+    }
+
+    #[test]
+    fn dollar_follows_the_user() {
+        let expected = if unsafe { libc::geteuid() } == 0 {
+            "#"
+        } else {
+            "$"
+        };
+        assert_eq!(render("\\$ ", &state()), format!("{expected} "));
+    }
+
+    #[test]
+    fn backslashes_and_unknown_escapes_are_kept() {
+        let state = state();
+        assert_eq!(render("a\\\\b", &state), "a\\b");
+        assert_eq!(render("\\q\\", &state), "\\q\\");
+        assert_eq!(render("\\[x\\]\\n", &state), "x\n");
+    }
+
+    #[test]
+    fn colors_become_escape_sequences() {
+        control::set_override(true);
+        let state = state();
+        assert_eq!(
+            render("\\{bold}\\{red}x\\{reset}", &state),
+            "\x1b[1m\x1b[31mx\x1b[0m"
+        );
+        assert_eq!(render("\\{nope}", &state), "\\{nope}");
+        assert_eq!(
+            render(DEFAULT_PS1, &state),
+            "\x1b[1m\x1b[94m~/src/shell/\x1b[0m\x1b[1m>\x1b[0m "
+        );
+        control::unset_override();
+    }
+}
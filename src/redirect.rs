@@ -0,0 +1,290 @@
+use crate::ast::Redirect;
+use crate::expand::{expand_here_doc, expand_word};
+use crate::lexer::{RedirectOp, here_doc_delimiter};
+use crate::state::ShellState;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug)]
+pub struct RedirectError {
+    target: String,
+    source: io::Error,
+}
+
+impl fmt::Display for RedirectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.target, self.source)
+    }
+}
+
+impl std::error::Error for RedirectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+static HERE_DOC_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn flush_std_streams() {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+}
+
+// Redirections are applied to the shell's own descriptors so that builtins
+// and spawned children see them alike. Dropping the guard puts the original
+// descriptors back.
+#[derive(Default)]
+pub struct RedirectGuard {
+    saved: Vec<(RawFd, Option<OwnedFd>)>,
+}
+
+impl RedirectGuard {
+    pub fn new() -> RedirectGuard {
+        RedirectGuard::default()
+    }
+
+    fn save(&mut self, fd: RawFd) -> io::Result<()> {
+        if self.saved.iter().any(|(saved_fd, _)| *saved_fd == fd) {
+            return Ok(());
+        }
+
+        flush_std_streams();
+        let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) };
+        let saved = if copy >= 0 {
+            Some(unsafe { OwnedFd::from_raw_fd(copy) })
+        } else {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EBADF) {
+                return Err(err);
+            }
+            None
+        };
+
+        self.saved.push((fd, saved));
+        Ok(())
+    }
+
+    pub fn redirect(&mut self, source: RawFd, target: RawFd) -> io::Result<()> {
+        self.save(target)?;
+        if source != target && unsafe { libc::dup2(source, target) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, target: RawFd) -> io::Result<()> {
+        self.save(target)?;
+        unsafe { libc::close(target) };
+        Ok(())
+    }
+
+    pub fn apply(
+        &mut self,
+        redirects: &[Redirect],
+        state: &mut ShellState,
+    ) -> Result<(), RedirectError> {
+        for redirect in redirects {
+            let target = match &redirect.body {
+                Some(body) if here_doc_delimiter(&redirect.target.raw).1 => Ok(body.clone()),
+                Some(body) => expand_here_doc(body, state),
+                None => expand_word(&redirect.target, state),
+            }
+            .map_err(|e| RedirectError {
+                target: redirect.target.to_string(),
+                source: io::Error::other(e),
+            })?;
+            self.apply_one(redirect, &target)
+                .map_err(|source| RedirectError { target, source })?;
+        }
+        Ok(())
+    }
+
+    fn apply_one(&mut self, redirect: &Redirect, target: &str) -> io::Result<()> {
+        match redirect.op {
+            RedirectOp::Input => {
+                let file = File::open(target)?;
+                self.redirect(file.as_raw_fd(), redirect.fd.unwrap_or(0))
+            }
+            RedirectOp::Output => {
+                let file = File::create(target)?;
+                self.redirect(file.as_raw_fd(), redirect.fd.unwrap_or(1))
+            }
+            RedirectOp::Append => {
+                let file = OpenOptions::new().append(true).create(true).open(target)?;
+                self.redirect(file.as_raw_fd(), redirect.fd.unwrap_or(1))
+            }
+            RedirectOp::ReadWrite => {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(target)?;
+                self.redirect(file.as_raw_fd(), redirect.fd.unwrap_or(0))
+            }
+            RedirectOp::OutputBoth => self.redirect_both(File::create(target)?),
+            RedirectOp::AppendBoth => {
+                self.redirect_both(OpenOptions::new().append(true).create(true).open(target)?)
+            }
+            RedirectOp::HereDoc | RedirectOp::HereDocStrip => {
+                self.feed(target, redirect.fd.unwrap_or(0))
+            }
+            RedirectOp::HereString => self.feed(&format!("{target}\n"), redirect.fd.unwrap_or(0)),
+            RedirectOp::DupInput | RedirectOp::DupOutput => {
+                let default_fd = if redirect.op == RedirectOp::DupInput {
+                    0
+                } else {
+                    1
+                };
+                let fd = redirect.fd.unwrap_or(default_fd);
+
+                if target == "-" {
+                    self.close(fd)
+                } else if let Ok(source) = target.parse::<RawFd>() {
+                    self.redirect(source, fd)
+                } else if redirect.op == RedirectOp::DupOutput && redirect.fd.is_none() {
+                    // `>& file` is the csh spelling of `&> file`
+                    self.redirect_both(File::create(target)?)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "ambiguous redirect",
+                    ))
+                }
+            }
+        }
+    }
+
+    // Gives `fd` a file to read `text` from. The text goes through an
+    // unlinked temporary file so that a long body cannot fill a pipe before
+    // anything reads it.
+    fn feed(&mut self, text: &str, fd: RawFd) -> io::Result<()> {
+        let path = env::temp_dir().join(format!(
+            "shell-here-doc-{}-{}",
+            std::process::id(),
+            HERE_DOC_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let reader = file
+            .write_all(text.as_bytes())
+            .and_then(|_| File::open(&path));
+        let _ = fs::remove_file(&path);
+        self.redirect(reader?.as_raw_fd(), fd)
+    }
+
+    fn redirect_both(&mut self, file: File) -> io::Result<()> {
+        self.redirect(file.as_raw_fd(), 1)?;
+        self.redirect(file.as_raw_fd(), 2)
+    }
+}
+
+impl Drop for RedirectGuard {
+    fn drop(&mut self) {
+        flush_std_streams();
+        for (fd, saved) in self.saved.drain(..).rev() {
+            match saved {
+                Some(saved) => unsafe { libc::dup2(saved.as_raw_fd(), fd) },
+                None => unsafe { libc::close(fd) },
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shell::Shell;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("shell-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn output_truncates_and_appends() {
+        let path = temp_file("output");
+        let name = path.display();
+        let mut shell = Shell::new();
+        shell.run_line(&format!("echo old > {name}")).unwrap();
+        shell.run_line(&format!("echo one > {name}")).unwrap();
+        shell.run_line(&format!("echo two >> {name}")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn input_reads_a_file() {
+        let path = temp_file("input");
+        fs::write(&path, "first\nsecond\n").unwrap();
+        let mut shell = Shell::new();
+        let output = shell
+            .eval_capture(&format!("head -n 1 < {}", path.display()))
+            .unwrap();
+        assert_eq!(output, "first");
+        assert!(shell.run_line("cat < /no/such/file").is_ok());
+        assert_ne!(shell.last_status(), 0);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn duplicates_follow_their_order() {
+        let mut shell = Shell::new();
+        let both = "sh -c 'echo out; echo err >&2'";
+        assert_eq!(
+            shell.eval_capture(&format!("{both} 2>&1")).unwrap(),
+            "out\nerr"
+        );
+        // stderr is pointed at the pipe before stdout leaves it
+        assert_eq!(
+            shell
+                .eval_capture(&format!("{both} 2>&1 > /dev/null"))
+                .unwrap(),
+            "err"
+        );
+        assert_eq!(
+            shell
+                .eval_capture(&format!("{both} > /dev/null 2>&1"))
+                .unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn both_streams_go_to_a_file() {
+        let path = temp_file("both");
+        let mut shell = Shell::new();
+        shell
+            .run_line(&format!(
+                "sh -c 'echo out; echo err >&2' &> {}",
+                path.display()
+            ))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "out\nerr\n");
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn descriptors_are_restored_after_a_function() {
+        let path = temp_file("restore");
+        let mut shell = Shell::new();
+        let output = shell
+            .eval_capture(&format!(
+                "f() {{ echo inside; echo error >&2; }}; f > {} 2>&1; echo after",
+                path.display()
+            ))
+            .unwrap();
+        assert_eq!(output, "after");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "inside\nerror\n");
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::commands::{Flow, capture_output, execute_input};
+use crate::parser::{ParseError, is_incomplete};
+use crate::state::{ReadonlyError, ShellState};
+use std::env;
+use std::io;
+use std::path::Path;
+
+// An interpreter that owns everything it runs with: variables, aliases,
+// functions, working directory, jobs and options. The binary drives one of
+// these, and other programs can embed it the same way.
+pub struct Shell {
+    state: ShellState,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shell {
+    pub fn new() -> Shell {
+        Shell {
+            state: ShellState::new(),
+        }
+    }
+
+    // Takes over the terminal for job control and passes hangups on to the
+    // jobs. Only an interactive shell wants this.
+    pub fn enable_job_control(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.state.jobs.enable()?;
+        self.state.jobs.forward_hangup()?;
+        Ok(())
+    }
+
+    pub fn state(&self) -> &ShellState {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut ShellState {
+        &mut self.state
+    }
+
+    pub fn last_status(&self) -> i32 {
+        self.state.last_status
+    }
+
+    pub fn var(&self, name: &str) -> Option<String> {
+        self.state.lookup(name)
+    }
+
+    pub fn set_var(&mut self, name: &str, value: &str) -> Result<(), ReadonlyError> {
+        self.state.set_var(name, value)
+    }
+
+    // Sets $0 and the positional parameters, as for a script run with
+    // arguments
+    pub fn set_args(&mut self, name: &str, args: Vec<String>) {
+        self.state.script_name = name.to_string();
+        self.state.positional = args;
+    }
+
+    pub fn cwd(&self) -> &Path {
+        &self.state.cwd
+    }
+
+    pub fn set_cwd(&mut self, dir: &Path) -> io::Result<()> {
+        self.state.change_dir(dir)
+    }
+
+    // The process has a single working directory, so it is moved to this
+    // shell's before anything runs
+    fn enter(&mut self) -> io::Result<()> {
+        if env::current_dir().ok().as_ref() != Some(&self.state.cwd) {
+            env::set_current_dir(&self.state.cwd)?;
+        }
+        Ok(())
+    }
+
+    // Runs one or more lines of shell code. A syntax error stops at the
+    // failing command, after everything before it has run.
+    pub fn run_line(&mut self, input: &str) -> Result<Flow, Box<dyn std::error::Error>> {
+        self.enter()?;
+        execute_input(input, &mut self.state).inspect_err(|e| {
+            self.state.last_status = if e.is::<ParseError>() { 2 } else { 1 };
+        })
+    }
+
+    pub fn run_script(&mut self, path: &Path) -> Result<Flow, Box<dyn std::error::Error>> {
+        let content = std::fs::read_to_string(path)?;
+        self.run_text(&content)
+    }
+
+    pub fn run_text(&mut self, text: &str) -> Result<Flow, Box<dyn std::error::Error>> {
+        self.run_lines(text.split_inclusive('\n').map(|line| Ok(line.to_string())))
+    }
+
+    // Runs lines of shell code one complete command at a time, taking only
+    // as many lines as the command needs. Commands run before a syntax error
+    // further on, and commands that read the same input see what follows.
+    pub fn run_lines(
+        &mut self,
+        lines: impl IntoIterator<Item = io::Result<String>>,
+    ) -> Result<Flow, Box<dyn std::error::Error>> {
+        let mut input = String::new();
+        let mut flow = Flow::Status(0);
+        for line in lines {
+            input.push_str(&line?);
+            if is_incomplete(&input) {
+                continue;
+            }
+            flow = self.run_line(&std::mem::take(&mut input))?;
+            if flow == Flow::Exit {
+                return Ok(flow);
+            }
+        }
+        // What is left is unfinished, and running it reports why
+        if !input.is_empty() {
+            flow = self.run_line(&input)?;
+        }
+        Ok(flow)
+    }
+
+    // Runs shell code in a subshell, as `$(...)` does, and returns what it
+    // wrote to stdout minus trailing newlines. Its exit status becomes this
+    // shell's last status.
+    pub fn eval_capture(&mut self, input: &str) -> Result<String, Box<dyn std::error::Error>> {
+        self.enter()?;
+        Ok(capture_output(input, &mut self.state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_follows_the_last_command() {
+        let mut shell = Shell::new();
+        shell.run_line("false").unwrap();
+        assert_eq!(shell.last_status(), 1);
+        shell.run_line("false; true").unwrap();
+        assert_eq!(shell.last_status(), 0);
+        shell.run_line("true && false").unwrap();
+        assert_eq!(shell.last_status(), 1);
+        shell.run_line("false || true").unwrap();
+        assert_eq!(shell.last_status(), 0);
+        shell.run_line("true | false").unwrap();
+        assert_eq!(shell.last_status(), 1);
+        shell.run_line("true | sh -c 'exit 3'").unwrap();
+        assert_eq!(shell.last_status(), 3);
+    }
+
+    #[test]
+    fn expansion_errors_abandon_the_line() {
+        let mut shell = Shell::new();
+        shell.state_mut().interactive = true;
+        shell.run_line("x=before; y=$((1/0)); x=after").unwrap();
+        assert_eq!(shell.var("x").as_deref(), Some("before"));
+        assert_eq!(shell.last_status(), 1);
+        shell.run_line("x=next").unwrap();
+        assert_eq!(shell.var("x").as_deref(), Some("next"));
+    }
+
+    #[test]
+    fn syntax_error_sets_status_two() {
+        let mut shell = Shell::new();
+        assert!(shell.run_line("echo )").is_err());
+        assert_eq!(shell.last_status(), 2);
+    }
+
+    #[test]
+    fn variables_persist_between_lines() {
+        let mut shell = Shell::new();
+        shell.run_line("x=1").unwrap();
+        shell.run_line("y=$((x + 1))").unwrap();
+        assert_eq!(shell.var("y").as_deref(), Some("2"));
+        shell.set_var("name", "world").unwrap();
+        assert_eq!(
+            shell.eval_capture("echo hello $name").unwrap(),
+            "hello world"
+        );
+    }
+
+    #[test]
+    fn functions_take_arguments_and_return_status() {
+        let mut shell = Shell::new();
+        shell.run_line("greet() { echo hi $1; }").unwrap();
+        assert_eq!(shell.eval_capture("greet there").unwrap(), "hi there");
+        shell.run_line("f() { return 4; }; f").unwrap();
+        assert_eq!(shell.last_status(), 4);
+    }
+
+    #[test]
+    fn captured_output_drops_trailing_newlines() {
+        let mut shell = Shell::new();
+        assert_eq!(shell.eval_capture("echo a; echo b; echo").unwrap(), "a\nb");
+        shell.eval_capture("echo x; false").unwrap();
+        assert_eq!(shell.last_status(), 1);
+    }
+
+    #[test]
+    fn captured_commands_run_in_a_subshell() {
+        let mut shell = Shell::new();
+        shell.eval_capture("unlikely_name=1").unwrap();
+        assert_eq!(shell.var("unlikely_name"), None);
+    }
+
+    #[test]
+    fn script_lines_run_until_a_syntax_error() {
+        let mut shell = Shell::new();
+        assert!(
+            shell
+                .run_text("a=1\nif true\nthen b=2\nfi\n)\nc=3\n")
+                .is_err()
+        );
+        assert_eq!(shell.var("a").as_deref(), Some("1"));
+        assert_eq!(shell.var("b").as_deref(), Some("2"));
+        assert_eq!(shell.var("c"), None);
+    }
+
+    #[test]
+    fn backslash_newline_continues_the_command() {
+        let mut shell = Shell::new();
+        shell
+            .run_text("count() { n=$#; }\ncount a \\\nb\n")
+            .unwrap();
+        assert_eq!(shell.var("n").as_deref(), Some("2"));
+        shell.run_text("echo a \\\nb\n").unwrap();
+        assert_eq!(shell.last_status(), 0);
+    }
+}
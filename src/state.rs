@@ -0,0 +1,242 @@
+use crate::ast::Function;
+use crate::git::GitPrompt;
+use crate::history::History;
+use crate::jobs::JobTable;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShellOptions {
+    // Patterns that match nothing are removed instead of kept as they are
+    pub nullglob: bool,
+    // Patterns that match nothing are an error and the command is not run
+    pub failglob: bool,
+}
+
+impl ShellOptions {
+    pub fn names(&self) -> Vec<(&'static str, bool)> {
+        vec![("failglob", self.failglob), ("nullglob", self.nullglob)]
+    }
+
+    pub fn set(&mut self, name: &str, value: bool) -> bool {
+        match name {
+            "failglob" => self.failglob = value,
+            "nullglob" => self.nullglob = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+// A pending `break` or `continue`, counting the loops it still has to
+// leave, or a `return` from the current function. `Abort` abandons all of
+// the input being run, after an error such as `${NAME:?}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jump {
+    Break(usize),
+    Continue(usize),
+    Return,
+    Abort,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Variable {
+    // None for a name that was only given attributes, as in `export NAME`
+    pub value: Option<String>,
+    pub exported: bool,
+    pub readonly: bool,
+}
+
+// Variables as they were before something changed them for a while, to be
+// put back with `ShellState::restore_variables`
+pub type SavedVariables = Vec<(String, Option<Variable>)>;
+
+#[derive(Debug)]
+pub struct ReadonlyError {
+    name: String,
+}
+
+impl fmt::Display for ReadonlyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: readonly variable", self.name)
+    }
+}
+
+impl std::error::Error for ReadonlyError {}
+
+pub struct ShellState {
+    pub variables: HashMap<String, Variable>,
+    pub cwd: PathBuf,
+    pub previous_dir: Option<PathBuf>,
+    pub last_status: i32,
+    // How long the last line entered at the prompt took to run
+    pub last_duration: Duration,
+    pub last_background_pid: Option<u32>,
+    pub script_name: String,
+    pub positional: Vec<String>,
+    pub jobs: JobTable,
+    // Set for a session at the prompt, which warns about failed commands
+    pub interactive: bool,
+    pub aliases: HashMap<String, String>,
+    // Set in forked children, whose failures the parent reports
+    pub in_subshell: bool,
+    // Set while running the condition of an if, while or until
+    pub in_condition: bool,
+    pub loop_depth: usize,
+    pub jump: Option<Jump>,
+    pub functions: HashMap<String, Rc<Function>>,
+    // Values saved by `local`, one scope per running function call
+    pub local_scopes: Vec<SavedVariables>,
+    pub options: ShellOptions,
+    // The line entered before the current one, which `edit` reopens
+    pub last_line: Option<String>,
+    pub history: History,
+    pub git: GitPrompt,
+}
+
+impl Default for ShellState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShellState {
+    pub fn new() -> ShellState {
+        ShellState {
+            variables: env::vars()
+                .map(|(name, value)| {
+                    let variable = Variable {
+                        value: Some(value),
+                        exported: true,
+                        readonly: false,
+                    };
+                    (name, variable)
+                })
+                .collect(),
+            cwd: env::current_dir().unwrap_or_else(|_| PathBuf::from("/")),
+            previous_dir: None,
+            last_status: 0,
+            last_duration: Duration::ZERO,
+            last_background_pid: None,
+            script_name: env::args().next().unwrap_or_else(|| "shell".to_string()),
+            positional: Vec::new(),
+            jobs: JobTable::new(),
+            interactive: false,
+            aliases: HashMap::new(),
+            in_subshell: false,
+            in_condition: false,
+            loop_depth: 0,
+            jump: None,
+            functions: HashMap::new(),
+            local_scopes: Vec::new(),
+            options: ShellOptions::default(),
+            last_line: None,
+            history: History::new(),
+            git: GitPrompt::default(),
+        }
+    }
+
+    fn special_parameter(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "$" => Some(std::process::id().to_string()),
+            "!" => Some(
+                self.last_background_pid
+                    .map(|pid| pid.to_string())
+                    .unwrap_or_default(),
+            ),
+            "#" => Some(self.positional.len().to_string()),
+            "@" | "*" => Some(self.positional.join(" ")),
+            "0" => Some(self.script_name.clone()),
+            _ => {
+                let index = name.parse::<usize>().ok()?.checked_sub(1)?;
+                Some(self.positional.get(index).cloned().unwrap_or_default())
+            }
+        }
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<String> {
+        self.special_parameter(name)
+            .or_else(|| self.variables.get(name)?.value.clone())
+    }
+
+    // Assigning keeps the variable's attributes, so an exported variable
+    // stays exported and a new one is private to the shell
+    pub fn set_var(&mut self, name: &str, value: &str) -> Result<(), ReadonlyError> {
+        let variable = self.variables.entry(name.to_string()).or_default();
+        if variable.readonly {
+            return Err(ReadonlyError {
+                name: name.to_string(),
+            });
+        }
+        variable.value = Some(value.to_string());
+        Ok(())
+    }
+
+    pub fn unset_var(&mut self, name: &str) -> Result<(), ReadonlyError> {
+        if self
+            .variables
+            .get(name)
+            .is_some_and(|variable| variable.readonly)
+        {
+            return Err(ReadonlyError {
+                name: name.to_string(),
+            });
+        }
+        self.variables.remove(name);
+        Ok(())
+    }
+
+    // Puts back variables saved before a function's locals or a command's
+    // assignments changed them
+    pub fn restore_variables(&mut self, saved: SavedVariables) {
+        for (name, variable) in saved.into_iter().rev() {
+            match variable {
+                Some(variable) => self.variables.insert(name, variable),
+                None => self.variables.remove(&name),
+            };
+        }
+    }
+
+    // The exported variables that have a value, which is all a child sees
+    pub fn environment(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.variables.iter().filter_map(|(name, variable)| {
+            let value = variable.value.as_ref().filter(|_| variable.exported)?;
+            Some((name, value))
+        })
+    }
+
+    // Moves the shell, and the process with it, to `dir`
+    pub fn change_dir(&mut self, dir: &Path) -> io::Result<()> {
+        env::set_current_dir(self.cwd.join(dir))?;
+        let cwd = env::current_dir()?;
+        let previous = std::mem::replace(&mut self.cwd, cwd);
+
+        let (pwd, oldpwd) = (
+            self.cwd.display().to_string(),
+            previous.display().to_string(),
+        );
+        // A readonly PWD only stops the variable from following the shell
+        let _ = self.set_var("PWD", &pwd);
+        let _ = self.set_var("OLDPWD", &oldpwd);
+        self.previous_dir = Some(previous);
+        Ok(())
+    }
+
+    // A command that runs with the shell's exported variables and working
+    // directory rather than whatever the process happens to have
+    pub fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut cmd = Command::new(program);
+        cmd.env_clear()
+            .envs(self.environment())
+            .current_dir(&self.cwd);
+        cmd
+    }
+}